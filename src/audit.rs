@@ -0,0 +1,138 @@
+//! # Audit
+//!
+//! The audit mod manages the `AUDIT` file of a repository, which
+//! records every redaction.  It's a text file, appended to and never
+//! rewritten.  A redaction is recorded as `redact <row> <time>` before
+//! the entry is erased, where `time` is in milliseconds since the Unix
+//! epoch, and as `done <row>` afterwards.
+//!
+//! Redactions are done one at a time, so only the last one can be
+//! unfinished after a crash.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::RowId;
+use crate::times::now_millis;
+
+/// A redaction recorded in the audit file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Redaction {
+    pub row: RowId,
+    /// When the redaction started.
+    pub time: SystemTime,
+    /// Whether the entry is known to be erased.  An unfinished
+    /// redaction is finished when the repository is opened for
+    /// writing.
+    pub done: bool,
+}
+
+/// The audit file of a writable repository.
+pub(crate) struct Audit {
+    file: File,
+}
+
+impl Audit {
+    /// Open the audit file, or create it if it doesn't exist.  A
+    /// partially written line at the end is dropped.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Audit> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(dir.as_ref().join("AUDIT"))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        if !text.is_empty() && !text.ends_with('\n') {
+            let end = text.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            file.set_len(end as u64)?;
+            file.sync_data()?;
+        }
+        Ok(Audit { file })
+    }
+
+    /// Durably record the start of a redaction.
+    pub fn begin(&self, row: RowId) -> Result<()> {
+        self.append(&format!("redact {} {}\n", row, now_millis()))
+    }
+
+    /// Durably record the end of a redaction.
+    pub fn finish(&self, row: RowId) -> Result<()> {
+        self.append(&format!("done {}\n", row))
+    }
+
+    fn append(&self, line: &str) -> Result<()> {
+        let mut file = &self.file;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+/// Load the redactions recorded in the audit file of the repository
+/// `dir`.
+pub(crate) fn load<P: AsRef<Path>>(dir: P) -> Result<Vec<Redaction>> {
+    let mut text = String::new();
+    match File::open(dir.as_ref().join("AUDIT")) {
+        Ok(mut file) => { file.read_to_string(&mut text)?; }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    let mut redactions: Vec<Redaction> = Vec::new();
+    // Only complete lines count.
+    for line in text.split_terminator('\n').take(text.matches('\n').count()) {
+        let parts: Vec<_> = line.split(' ').collect();
+        match parts[..] {
+            ["redact", row, time] => redactions.push(Redaction {
+                row: row.parse().map_err(|_| invalid_data(line))?,
+                time: UNIX_EPOCH + Duration::from_millis(time.parse().map_err(|_| invalid_data(line))?),
+                done: false,
+            }),
+            ["done", row] => {
+                let row: RowId = row.parse().map_err(|_| invalid_data(line))?;
+                match redactions.last_mut() {
+                    Some(last) if last.row == row => last.done = true,
+                    _ => return Err(invalid_data(line)),
+                }
+            }
+            _ => return Err(invalid_data(line)),
+        }
+    }
+    Ok(redactions)
+}
+
+fn invalid_data(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("malformed AUDIT entry: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::tests::*;
+
+    #[test]
+    fn begin_and_finish() {
+        ensure_dir_nonexistent("dbaudit");
+        fs::create_dir("dbaudit").unwrap();
+
+        let audit = Audit::open("dbaudit").unwrap();
+        audit.begin(3).unwrap();
+        audit.finish(3).unwrap();
+        audit.begin(5).unwrap();
+        let redactions = load("dbaudit").unwrap();
+        assert_eq!(redactions.iter().map(|r| (r.row, r.done)).collect::<Vec<_>>(), vec![(3, true), (5, false)]);
+        drop(audit);
+
+        // A torn line is dropped.
+        OpenOptions::new().append(true).open("dbaudit/AUDIT").unwrap().write_all(b"done").unwrap();
+        assert_eq!(load("dbaudit").unwrap().len(), 2);
+        let audit = Audit::open("dbaudit").unwrap();
+        audit.finish(5).unwrap();
+        assert!(load("dbaudit").unwrap()[1].done);
+
+        fs::remove_dir_all("dbaudit").unwrap();
+    }
+}
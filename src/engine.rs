@@ -1,31 +1,245 @@
-use std::io::{Result, Error};
+use std::fmt;
+use std::io::{Result, Error, ErrorKind};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::collections::HashMap;
-use libc::{ENOTDIR, EBUSY};
-use byteorder::LE;
-use positioned_io::{ReadAt, ReadBytesAtExt};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, Condvar, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use libc::{ENOTDIR, EBUSY, EROFS};
 
-use crate::{Index, Log, RowId};
+use crate::RowId;
+use crate::meta::Meta;
+use crate::audit::{self, Audit, Redaction};
+use crate::hooks::{Hooks, PendingCommit};
+use crate::manifest::{Entry, Manifest};
+use crate::operation::{self, Operation, Operations};
+use crate::segment::{self, LogCache, Segment, SegmentInfo, Tier};
+use crate::times::now_millis;
+use crate::index::IndexMode;
 use crate::index::Transaction as IndexTx;
+use crate::log::CacheBudget;
 use crate::log::Transaction as LogTx;
-use crate::log::EntrySize;
+use crate::flex::{DEFAULT_FLEX_SIZE, EvictionPolicy};
+use crate::watch::Watcher;
+
+/// Width of row IDs, as recorded in the meta file.
+const ROW_ID_BITS: usize = std::mem::size_of::<RowId>() * 8;
+
+/// Default size of a segment before rolling over to a new one.
+const DEFAULT_SEGMENT_SIZE: u64 = 1 << 30;
+
+/// Default number of rows an operation applies between checkpoints.
+const DEFAULT_CHECKPOINT_ROWS: RowId = 1 << 16;
 
 /// The log engine.
 pub struct Engine {
-    index: Index,
-    log: Log,
-    lock_path: PathBuf,
+    shared: Arc<Shared>,
+    /// The segment new rows are appended to.
+    active: Arc<Segment>,
+    path: PathBuf,
+    options: OpenOptions,
+    /// Stride of the index files of the repository.
+    stride: usize,
+    /// The lock file held by a writer.  Read-only engines don't take
+    /// the lock.
+    lock_path: Option<PathBuf>,
+    /// Watches the repository directory of a read-only engine.
+    watcher: Option<Watcher>,
+    /// The audit file of a writable engine.
+    audit: Option<Audit>,
+    /// User-defined operations, applied to committed rows.
+    ops: Mutex<Operations>,
+    hooks: Hooks,
 
     info: HashMap<Box<[u8]>, Box<[u8]>>,
 }
 
-impl Engine {
-    /// Open an existing log repository, or create it if it doesn't
-    /// exist.
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Engine> {
+/// Options and flags which can be used to configure how an engine is
+/// opened.
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    read_only: bool,
+    index_mode: IndexMode,
+    stride: Option<usize>,
+    segment_size: Option<u64>,
+    segment_rows: Option<RowId>,
+    retain_bytes: Option<u64>,
+    retain_age: Option<Duration>,
+    retain_rows: Option<RowId>,
+    cold_dir: Option<PathBuf>,
+    cold_after: Option<Duration>,
+    checkpoint_rows: Option<RowId>,
+    cache_size: Option<usize>,
+    cache_policy: EvictionPolicy,
+    write_buffer: Option<usize>,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    /// Open the repository for reading only.
+    ///
+    /// A read-only engine doesn't take the writer lock, so it can
+    /// follow a writer in another process.  The repository must
+    /// already exist.  New rows committed by the writer show up after
+    /// `Engine::refresh`.
+    pub fn read_only(&mut self, read_only: bool) -> &mut OpenOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Choose how the index is kept in memory.  See `IndexMode`.  Only
+    /// `IndexMode::Mapped` opens a repository in constant time.
+    pub fn index_mode(&mut self, mode: IndexMode) -> &mut OpenOptions {
+        self.index_mode = mode;
+        self
+    }
+
+    /// Record only the offset of every `stride`-th row in the index.
+    /// Looking up other rows skips entries in the log file, starting
+    /// from the nearest recorded row.  A larger stride means a smaller
+    /// index but slower lookups.  A stride of 1 means a dense index.
+    ///
+    /// The stride is fixed once the repository has any data.  If this
+    /// is not set, the stride of the repository is used.
+    ///
+    /// In a sparse repository, a transaction is durable as soon as
+    /// its entries are written to the log file.
+    pub fn sparse(&mut self, stride: usize) -> &mut OpenOptions {
+        assert!(stride > 0);
+        self.stride = Some(stride);
+        self
+    }
+
+    /// Roll over to a new segment once the active one takes `bytes`
+    /// bytes on disk.  The default is 1 GiB.
+    ///
+    /// The limits are checked when a transaction starts, so a
+    /// transaction never spans two segments, and a segment may exceed
+    /// the limits by the size of its last transaction.
+    pub fn segment_size(&mut self, bytes: u64) -> &mut OpenOptions {
+        self.segment_size = Some(bytes);
+        self
+    }
+
+    /// Roll over to a new segment once the active one holds `rows`
+    /// rows.  There is no limit by default.  See `segment_size`.
+    pub fn segment_rows(&mut self, rows: RowId) -> &mut OpenOptions {
+        assert!(rows > 0);
+        self.segment_rows = Some(rows);
+        self
+    }
+
+    /// Keep at most about `bytes` bytes of segments on disk.
+    ///
+    /// Retention limits are enforced after each commit, by truncating
+    /// old rows as `Engine::truncate_before` does.  Failures don't
+    /// fail the commit, and go to `Engine::add_error_hook` instead.
+    /// Since disk space is reclaimed by whole segments, the size limit
+    /// only removes sealed segments, and the active segment is always
+    /// kept.
+    pub fn retain_bytes(&mut self, bytes: u64) -> &mut OpenOptions {
+        self.retain_bytes = Some(bytes);
+        self
+    }
+
+    /// Discard rows committed longer than `age` ago.  Rows written
+    /// before commit times were recorded count as older than any
+    /// recorded commit.  See `retain_bytes`.
+    pub fn retain_age(&mut self, age: Duration) -> &mut OpenOptions {
+        self.retain_age = Some(age);
+        self
+    }
+
+    /// Keep at most the last `rows` rows.  See `retain_bytes`.
+    pub fn retain_rows(&mut self, rows: RowId) -> &mut OpenOptions {
+        self.retain_rows = Some(rows);
+        self
+    }
+
+    /// Keep cold segments in `dir`, typically on larger and slower
+    /// storage.  The directory is created if it doesn't exist, and
+    /// must not be shared with other repositories.  A repository with
+    /// cold segments can't be opened without it.
+    pub fn cold_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut OpenOptions {
+        self.cold_dir = Some(PathBuf::from(dir.as_ref()));
+        self
+    }
+
+    /// Move the sealed segments last committed to longer than `age`
+    /// ago to the cold storage directory.  Segments are moved by
+    /// `Engine::move_cold`, and are read from there transparently.
+    /// See `cold_dir`.
+    pub fn cold_after(&mut self, age: Duration) -> &mut OpenOptions {
+        self.cold_after = Some(age);
+        self
+    }
+
+    /// Checkpoint the state of each operation once it has applied
+    /// `rows` rows since its last checkpoint.  The default is 65536.
+    /// See `Engine::register`.
+    pub fn checkpoint_rows(&mut self, rows: RowId) -> &mut OpenOptions {
+        assert!(rows > 0);
+        self.checkpoint_rows = Some(rows);
+        self
+    }
+
+    /// Cache up to `bytes` bytes of the log files in memory, so that
+    /// reading hot rows doesn't touch the disk.  The budget is split
+    /// evenly between the segments.  The default is 64 MiB, and 0
+    /// disables the cache.
+    ///
+    /// Readers of the same segment take turns on its cache, while
+    /// readers of different segments don't wait for each other.
+    pub fn cache_size(&mut self, bytes: usize) -> &mut OpenOptions {
+        self.cache_size = Some(bytes);
+        self
+    }
+
+    /// Choose which cached pages of a log file to evict.  The default
+    /// is 2Q, which keeps large sequential scans from pushing out the
+    /// pages of hot rows.
+    pub fn cache_policy(&mut self, policy: EvictionPolicy) -> &mut OpenOptions {
+        self.cache_policy = policy;
+        self
+    }
+
+    /// Buffer up to `bytes` bytes of the entries appended by a
+    /// transaction in memory, before writing them to the log file.
+    /// Larger transactions write back as they go.  The default is 64
+    /// MiB.
+    pub fn write_buffer(&mut self, bytes: usize) -> &mut OpenOptions {
+        self.write_buffer = Some(bytes);
+        self
+    }
+
+    /// How to cache the log files, with a new budget for them.
+    fn log_cache(&self) -> LogCache {
+        LogCache {
+            budget: CacheBudget::new(self.cache_size.unwrap_or(DEFAULT_FLEX_SIZE)),
+            policy: self.cache_policy,
+            write_buffer: self.write_buffer.unwrap_or(DEFAULT_FLEX_SIZE),
+        }
+    }
+
+    /// The directory holding the segments of the storage `tier` of the
+    /// repository `path`.
+    fn tier_dir<'a>(&'a self, path: &'a Path, tier: Tier) -> Result<&'a Path> {
+        match (tier, &self.cold_dir) {
+            (Tier::Hot, _) => Ok(path),
+            (Tier::Cold, Some(dir)) => Ok(dir),
+            (Tier::Cold, None) => Err(Error::new(ErrorKind::NotFound, "cold storage directory is not configured")),
+        }
+    }
+
+    /// Open a log repository with the options.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Engine> {
         // Check if path exists.
-        if Path::exists(path.as_ref()) {
+        if self.read_only || Path::exists(path.as_ref()) {
             // Check if the target is directory.
             let metadata = fs::metadata(path.as_ref())?;
             if !metadata.is_dir() {
@@ -36,73 +250,406 @@ impl Engine {
         }
 
         // Check and create the lock file.
-        let lock_path = {
+        let lock_path = if self.read_only {
+            None
+        } else {
             let mut buf = PathBuf::from(path.as_ref());
             buf.push("LOCK");
-            buf
+            Some(buf)
         };
-        if Path::exists(&lock_path) {
-            return Err(Error::from_raw_os_error(EBUSY));
-        } else {
-            fs::OpenOptions::new().create(true).write(true).open(&lock_path)?;
+        if let Some(lock_path) = &lock_path {
+            if Path::exists(lock_path) {
+                return Err(Error::from_raw_os_error(EBUSY));
+            } else {
+                fs::OpenOptions::new().create(true).write(true).open(lock_path)?;
+            }
         }
 
-        // Open the index file.
-        let index_path = {
-            let mut buf = PathBuf::from(path.as_ref());
-            buf.push("IDX0");
-            buf
+        // Don't leave the lock behind if the repository can't be opened.
+        let engine = self.open_locked(path.as_ref(), lock_path.clone());
+        if let (Err(_), Some(lock_path)) = (&engine, &lock_path) {
+            let _ = fs::remove_file(lock_path);
+        }
+        engine
+    }
+
+    fn open_locked(&self, path: &Path, lock_path: Option<PathBuf>) -> Result<Engine> {
+        // Watch before loading, so that no changes are missed.
+        let watcher = if self.read_only {
+            Some(Watcher::new(path)?)
+        } else {
+            None
         };
-        let index = Index::open(&index_path)?;
 
-        // Open the log file.
-        let log_path = {
-            let mut buf = PathBuf::from(path.as_ref());
-            buf.push("LOG0");
-            buf
+        let mut manifest = Manifest::load(path)?;
+        let mut meta = Meta::load(path)?;
+        let mut meta_changed = false;
+
+        // Row IDs are not stored in the index file, so a repository
+        // written with 32-bit row IDs is migrated by just recording the
+        // new width.
+        match meta.get::<usize>("row_id_bits")? {
+            Some(ROW_ID_BITS) => (),
+            None | Some(32) => {
+                meta.set("row_id_bits", ROW_ID_BITS);
+                meta_changed = true;
+            }
+            Some(_) => return Err(Error::new(ErrorKind::InvalidData, "unsupported row ID width")),
+        }
+
+        // The stride can only be chosen for an empty repository.
+        let mut stride = meta.get("index_stride")?.unwrap_or(1);
+        if let Some(new_stride) = self.stride {
+            if new_stride != stride {
+                let file_empty = |path: PathBuf| fs::metadata(path).map(|m| m.len() == 0).unwrap_or(true);
+                let first = manifest.segments[0].id;
+                let empty = manifest.segments.len() == 1
+                    && file_empty(segment::log_path(path, first))
+                    && file_empty(segment::index_path(path, first));
+                if self.read_only || !empty {
+                    return Err(Error::new(ErrorKind::InvalidInput, "index stride mismatch"))
+                }
+                meta.set("index_stride", new_stride);
+                meta_changed = true;
+                stride = new_stride;
+            }
+        }
+
+        if meta_changed && !self.read_only {
+            meta.store(path)?;
+        }
+
+        if !self.read_only {
+            segment::remove_before(path, manifest.segments[0].id)?;
+            if let Some(cold_dir) = &self.cold_dir {
+                fs::create_dir_all(cold_dir)?;
+                segment::remove_before(cold_dir, manifest.segments[0].id)?;
+            }
+
+            // Finish an interrupted compaction, or clean up after one
+            // which didn't get that far.
+            if let Some(id) = manifest.compacting {
+                segment::finish_compaction(self.tier_dir(path, manifest.tier(id))?, id)?;
+                manifest.compacting = None;
+                manifest.store(path)?;
+            }
+            for entry in &manifest.segments {
+                segment::remove_compacted(self.tier_dir(path, manifest.tier(entry.id))?, entry.id)?;
+            }
+
+            // Clean up after an interrupted move to the cold tier.  The
+            // manifest tells which copy is complete.
+            for entry in &manifest.segments {
+                match (manifest.tier(entry.id), &self.cold_dir) {
+                    (Tier::Cold, _) => segment::remove(path, entry.id)?,
+                    (Tier::Hot, Some(cold_dir)) => segment::remove(cold_dir, entry.id)?,
+                    (Tier::Hot, None) => (),
+                }
+            }
+        }
+        let log_cache = self.log_cache();
+        let segments = manifest.segments.iter()
+            .map(|&entry| {
+                let tier = manifest.tier(entry.id);
+                Segment::open(self.tier_dir(path, tier)?, tier, entry, self.index_mode, stride, self.read_only, log_cache.clone()).map(Arc::new)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Finish an interrupted tail truncation.
+        if let (Some(end), false) = (manifest.truncating, self.read_only) {
+            manifest = finish_truncate_after(path, self.cold_dir.as_deref(), manifest, &segments, end)?;
+        }
+
+        // Only the deletions and updates of committed transactions
+        // take effect.
+        let next_row = segments.last().unwrap().end();
+        for segment in &segments {
+            if self.read_only {
+                segment.load_changes(next_row)?;
+            } else {
+                segment.truncate_changes(next_row)?;
+                // Dead ranges are not stored, so they're found again.
+                let first = manifest.first_row.saturating_sub(segment.base());
+                let deleted: Vec<_> = segment.tombstones.rows().into_iter().filter(|&row| row >= first).collect();
+                segment.mark_deleted(&deleted)?;
+                segment.mark_truncated(manifest.first_row)?;
+            }
+        }
+
+        let audit = if self.read_only {
+            None
+        } else {
+            Some(Audit::open(path)?)
         };
-        let log = Log::open(&log_path)?;
 
-        Ok(Engine {
-            index,
-            log,
+        let engine = Engine {
+            active: segments.last().unwrap().clone(),
+            shared: Arc::new(Shared {
+                segments: RwLock::new(segments),
+                first_row: AtomicU64::new(manifest.first_row),
+                generation: AtomicU64::new(manifest.generation),
+                cut: Mutex::new((manifest.generation, next_row)),
+                log_cache,
+                closed: Mutex::new(false),
+                committed: Condvar::new(),
+            }),
+            path: PathBuf::from(path),
+            options: self.clone(),
+            stride,
             info: HashMap::new(),
             lock_path,
-        })
+            watcher,
+            audit,
+            ops: Mutex::new(Operations::default()),
+            hooks: Hooks::default(),
+        };
+
+        // Finish an interrupted redaction.
+        if !self.read_only {
+            if let Some(redaction) = audit::load(path)?.last().filter(|r| !r.done) {
+                engine.finish_redaction(redaction.row)?;
+            }
+        }
+        Ok(engine)
+    }
+}
+
+impl Engine {
+    /// Open an existing log repository, or create it if it doesn't
+    /// exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Engine> {
+        OpenOptions::new().open(path)
+    }
+
+    /// Open an existing log repository for reading only.  See
+    /// `OpenOptions::read_only`.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Engine> {
+        OpenOptions::new().read_only(true).open(path)
+    }
+
+    /// Whether this engine is opened for reading only.
+    pub fn is_read_only(&self) -> bool {
+        self.lock_path.is_none()
+    }
+
+    /// Pick up the rows committed by the writer since the last
+    /// refresh, and return the number of new rows.
+    ///
+    /// Only the newly appended tails of the index files are read, and
+    /// segments added by the writer are opened.  If the writer removed
+    /// rows from the end, as the generation in the manifest tells, all
+    /// segments are opened again instead.  This is a no-op for a
+    /// writable engine, which always sees its own commits.
+    pub fn refresh(&self) -> Result<usize> {
+        if !self.is_read_only() {
+            return Ok(0)
+        }
+        let start = self.next_row();
+
+        // A segment is sealed before it's replaced as the last one in
+        // the manifest, so refreshing the old last segment after
+        // loading the manifest doesn't miss any of its rows.
+        let manifest = Manifest::load(&self.path)?;
+        let truncated = manifest.generation != self.shared.generation.load(Ordering::SeqCst);
+        let old = if truncated {
+            Vec::new()
+        } else {
+            self.shared.segments.read().unwrap().clone()
+        };
+        // Segments removed by the writer are forgotten, and those moved
+        // to another tier are opened again.  Of the others, only the
+        // last one may have changed.
+        let mut segments = Vec::new();
+        let mut last_kept = None;
+        for &entry in &manifest.segments {
+            let tier = manifest.tier(entry.id);
+            match old.iter().find(|segment| segment.entry() == entry && segment.tier() == tier) {
+                Some(segment) => {
+                    last_kept = Some(segment.clone());
+                    segments.push(segment.clone());
+                }
+                None => {
+                    let dir = self.options.tier_dir(&self.path, tier)?;
+                    segments.push(Arc::new(Segment::open(dir, tier, entry, self.options.index_mode, self.stride, true, self.shared.log_cache.clone())?));
+                }
+            }
+        }
+        if let Some(segment) = last_kept {
+            segment.refresh()?;
+        }
+
+        // Lower the first row before publishing the segments of a reset
+        // repository, so that it's never after the next row.
+        self.shared.first_row.store(manifest.first_row, Ordering::SeqCst);
+        self.shared.generation.store(manifest.generation, Ordering::SeqCst);
+        let next_row = segments.last().unwrap().end();
+        for segment in &segments {
+            segment.load_changes(next_row)?;
+        }
+        *self.shared.segments.write().unwrap() = segments;
+        if truncated {
+            self.shared.cut_tail(manifest.generation, next_row);
+        }
+
+        let n = self.next_row().saturating_sub(start) as usize;
+        if n > 0 {
+            self.shared.notify();
+        }
+        let mut ops = self.ops.lock().unwrap();
+        if truncated {
+            // The operations may have applied removed rows.  The writer
+            // removes the checkpoints covering them first.
+            ops.reload(&self.path)?;
+        }
+        self.shared.apply_operations(&self.path, &mut ops)?;
+        Ok(n)
+    }
+
+    /// Wait at most `timeout` for new rows, and return the number of
+    /// rows that became visible.  Returns early if rows are removed
+    /// from the end in the meantime, see `truncate_after` and `reset`.
+    ///
+    /// A read-only engine refreshes itself, and on Linux, sleeps on
+    /// inotify events of the repository directory in between.
+    pub fn wait_for_rows(&self, timeout: Duration) -> Result<usize> {
+        let deadline = Instant::now() + timeout;
+        let start = self.next_row();
+        let generation = self.shared.cut().0;
+        match &self.watcher {
+            None => {
+                self.shared.wait_for(start, generation, Some(deadline));
+            }
+            Some(watcher) => {
+                loop {
+                    self.refresh()?;
+                    let now = Instant::now();
+                    if self.next_row() > start || self.shared.cut().0 != generation || now >= deadline {
+                        break
+                    }
+                    watcher.wait(deadline - now)?;
+                }
+            }
+        }
+        Ok(self.next_row().saturating_sub(start) as usize)
     }
 
     /// Get an entry from the log file.
     ///
     /// Precisely, this method just reads the log file at a certain
-    /// offset.
+    /// offset.  Reading a row discarded by `truncate_before` fails with
+    /// a `Truncated` error.
     pub fn get(&self, row: RowId) -> Result<Option<Box<[u8]>>> {
-        match self.index.get(row)? {
-            Some(offset) => {
-                unsafe { let _ = std::mem::transmute::<u16, EntrySize>(0); }
-                let len = self.log.read_u16_at::<LE>(offset)?;
-                let mut buf = vec![0; len as usize];
-                self.log.read_at(offset + std::mem::size_of::<u16>() as u64, &mut buf[..])?;
-                return Ok(Some(buf.into_boxed_slice()))
-            }
-            None => Ok(None),
-        }
+        self.shared.get(row)
+    }
+
+    /// Get a version of an entry: version 0 is the appended entry, and
+    /// the later ones are its updates by `Transaction::update`.  `get`
+    /// returns the latest version.
+    ///
+    /// Returns `None` if the row or the version doesn't exist.  Only
+    /// the latest version is kept by `compact`, as version 0.
+    pub fn get_version(&self, row: RowId, version: usize) -> Result<Option<Box<[u8]>>> {
+        self.shared.get_version(row, Some(version))
+    }
+
+    /// Get the length of an entry, without reading its contents.
+    pub fn entry_len(&self, row: RowId) -> Result<Option<usize>> {
+        self.shared.entry_len(row)
+    }
+
+    /// Read `len` bytes of an entry, starting from byte `start` of
+    /// the entry.  Only the requested bytes are read from the log
+    /// file.
+    ///
+    /// Fails with `InvalidInput` if the range exceeds the entry.
+    pub fn read_range(&self, row: RowId, start: usize, len: usize) -> Result<Option<Box<[u8]>>> {
+        self.shared.read_range(row, start, len)
+    }
+
+    /// Warm the log cache up for a scan of `rows`, by reading their
+    /// entries ahead in large chunks.  Rows which are truncated or not
+    /// committed are ignored.  Each log file only prefetches as much
+    /// as its cache holds, so long ranges are better warmed up piece
+    /// by piece as the scan goes.
+    pub fn prefetch(&self, rows: Range<RowId>) -> Result<()> {
+        self.shared.prefetch(rows)
     }
 
     /// Get the next row ID.
     pub fn next_row(&self) -> RowId {
-        self.index.next_row()
+        self.shared.next_row()
+    }
+
+    /// Get the first row which is not truncated.
+    pub fn first_row(&self) -> RowId {
+        self.shared.first_row()
     }
 
-    /// Number of existing entries.
+    /// Number of segments of the repository.
+    pub fn segment_count(&self) -> usize {
+        self.shared.segments.read().unwrap().len()
+    }
+
+    /// List the segments of the repository in order, with the storage
+    /// tier each one is in.
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        self.shared.segments.read().unwrap().iter().map(|segment| segment.info()).collect()
+    }
+
+    /// Number of existing entries, not counting truncated ones.
     pub fn count(&self) -> usize {
-        self.next_row() as usize
+        (self.next_row() - self.first_row()) as usize
+    }
+
+    /// Get a reader handle, which can be cloned and sent to other
+    /// threads.
+    ///
+    /// Readers see all committed rows, and keep working while a
+    /// transaction is open.
+    pub fn reader(&self) -> Reader {
+        Reader {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Follow the log from `from_row` on, like `tail -f`.
+    ///
+    /// The subscription yields committed rows in order, and waits for
+    /// new commits once it has caught up.  If the rows it is about to
+    /// yield are truncated, it yields a `Truncated` error once, and
+    /// goes on from the first row.  If they are removed from the end
+    /// by `truncate_after` or `reset`, it yields a `Rewound` error once,
+    /// and goes on from the new next row, so that rows written there
+    /// again are not missed.
+    pub fn subscribe(&self, from_row: RowId) -> Subscription {
+        self.reader().subscribe(from_row)
     }
 
     /// Start a transaction.
-    pub fn transaction(&mut self) -> Result<Transaction> {
+    ///
+    /// If the active segment is full, a new segment is started first.
+    /// Fails with `EROFS` if the engine is read-only.
+    pub fn transaction(&mut self) -> Result<Transaction<'_>> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        if self.segment_full()? {
+            self.roll_over()?;
+        }
         Ok(Transaction {
-            log_tx: self.log.transaction()?,
-            index_tx: self.index.transaction(),
+            shared: &self.shared,
+            path: &self.path,
+            options: &self.options,
+            active: &self.active,
+            start: self.active.rows(),
+            appended: 0,
+            deletes: Vec::new(),
+            updates: Vec::new(),
+            log_tx: self.active.log.begin()?,
+            index_tx: self.active.index.begin(),
+            ops: &self.ops,
+            hooks: &mut self.hooks,
+            entries: Vec::new(),
             info: &mut self.info,
             info_updates: HashMap::new(),
         })
@@ -113,54 +660,1117 @@ impl Engine {
         &self.info
     }
 
+    /// Register a user-defined operation as `name`.  Its state is
+    /// restored from its last checkpoint, if any, and it's applied to
+    /// the committed rows after that, and then to the rows of every
+    /// commit, or of every `refresh` of a follower.
+    ///
+    /// Each row is applied once, as it reads at that time.  Before an
+    /// applied row is deleted, updated, redacted or truncated, the
+    /// operations are checkpointed, so that they never replay it as it
+    /// reads afterwards.  Rows not applied yet are applied as they
+    /// read then: deleted and truncated rows are skipped, and updated
+    /// rows give their latest versions.  Followers apply rows as they
+    /// see them on `refresh`.  Names are made of ASCII
+    /// letters, digits, `_` and `-`.  Fails with `AlreadyExists` if
+    /// `name` is taken.
+    pub fn register<O: Operation + 'static>(&mut self, name: &str, op: O) -> Result<()> {
+        // A follower catches up first, so that the checkpoint isn't
+        // after its next row.
+        self.refresh()?;
+        let ops = self.ops.get_mut().unwrap();
+        ops.register(&self.path, name, op)?;
+        self.shared.apply_operations(&self.path, ops)
+    }
+
+    /// Call `f` with the operation registered as `name`, if it's an
+    /// `O`.
+    pub fn with_operation<O, R, F>(&self, name: &str, f: F) -> Option<R>
+    where
+        O: Operation + 'static,
+        F: FnOnce(&O) -> R,
+    {
+        self.ops.lock().unwrap().get(name).map(f)
+    }
+
+    /// Call `hook` before each transaction commits, with the entries,
+    /// deletions, updates and info updates it's about to commit.  If the hook returns an
+    /// error, the transaction is rolled back, and `commit` returns the
+    /// error.  Hooks run in the order they're added, up to the first
+    /// error.
+    pub fn add_pre_commit_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&PendingCommit<'_>) -> Result<()> + Send + 'static,
+    {
+        self.hooks.add_pre_commit(Box::new(hook));
+    }
+
+    /// Call `hook` after each transaction commits, with the range of
+    /// the rows it appended, once they're durable.  The range is empty
+    /// if the transaction appended nothing.
+    pub fn add_post_commit_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(Range<RowId>) + Send + 'static,
+    {
+        self.hooks.add_post_commit(Box::new(hook));
+    }
+
+    /// Call `hook` with each error of the work after a commit:
+    /// recording its time, loading its deletions and updates, applying
+    /// the operations, or enforcing the retention limits.  Such errors don't fail the
+    /// commit, whose rows are durable by then, and are also logged as
+    /// warnings.  The work is tried again after the next commit.
+    pub fn add_error_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&Error) + Send + 'static,
+    {
+        self.hooks.add_error(Box::new(hook));
+    }
+
+    /// Checkpoint the states of the operations which applied rows
+    /// since their last checkpoints.  Writable engines also do this
+    /// when dropped.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        self.ops.get_mut().unwrap().checkpoint(&self.path, 1)
+    }
+
     /// Reset the whole database, drop all existing entries and index,
-    /// and possibibly truncate the log file and the index file.
+    /// and remove all segments.  The next row is 0 afterwards.
+    ///
+    /// An empty segment replaces all segments in the manifest at once,
+    /// so a crash leaves either the old rows or none of them.
     pub fn reset(&mut self) -> Result<()> {
-        self.log.reset()?;
-        self.index.reset()?;
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        let entry = Entry {
+            id: self.active.id() + 1,
+            base: 0,
+        };
+        let segment = Arc::new(Segment::open(&self.path, Tier::Hot, entry, self.options.index_mode, self.stride, false, self.shared.log_cache.clone())?);
+        operation::remove_checkpoints_after(&self.path, 0)?;
+        let generation = self.shared.generation.load(Ordering::SeqCst) + 1;
+        Manifest { first_row: 0, segments: vec![entry], generation, ..Manifest::default() }.store(&self.path)?;
+        self.shared.generation.store(generation, Ordering::SeqCst);
+
+        self.shared.first_row.store(0, Ordering::SeqCst);
+        let old = std::mem::replace(&mut *self.shared.segments.write().unwrap(), vec![segment.clone()]);
+        self.active = segment;
+        self.shared.cut_tail(generation, 0);
+        for segment in old {
+            segment::remove(segment.dir(), segment.id())?;
+        }
+        self.shared.apply_operations(&self.path, self.ops.get_mut().unwrap())
+    }
+
+    /// Remove all rows after `row`, so that the next row is `row + 1`.
+    ///
+    /// The truncation is recorded in the manifest before any file is
+    /// touched, and finished on the next open after a crash.  Readers
+    /// reading the removed rows at the same time may get errors.
+    /// Followers pick up the truncation on `refresh`.
+    ///
+    /// Fails with `InvalidInput` if `row` is not committed or already
+    /// truncated.
+    pub fn truncate_after(&mut self, row: RowId) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        if row >= self.next_row() {
+            return Err(Error::new(ErrorKind::InvalidInput, "row is not committed yet"))
+        }
+        if row < self.first_row() {
+            return Err(Error::new(ErrorKind::InvalidInput, "row is truncated"))
+        }
+        let end = row + 1;
+        if end == self.next_row() {
+            return Ok(())
+        }
+
+        let mut manifest = self.shared.manifest();
+        manifest.segments.retain(|entry| entry.base <= row);
+        manifest.truncating = Some(end);
+        manifest.generation += 1;
+        manifest.store(&self.path)?;
+        self.shared.generation.store(manifest.generation, Ordering::SeqCst);
+
+        let last = manifest.segments.last().unwrap().id;
+        self.shared.segments.write().unwrap().retain(|segment| segment.id() <= last);
+        self.active = self.shared.last_segment();
+        let segments = self.shared.segments.read().unwrap().clone();
+        let manifest = finish_truncate_after(&self.path, self.options.cold_dir.as_deref(), manifest, &segments, end)?;
+        self.shared.generation.store(manifest.generation, Ordering::SeqCst);
+        self.shared.cut_tail(manifest.generation, end);
+        self.shared.apply_operations(&self.path, self.ops.get_mut().unwrap())
+    }
+
+    /// Rewrite the sealed segments with deleted or updated rows,
+    /// dropping the payloads of the deleted rows and the old versions
+    /// of the updated rows, and return the number of bytes reclaimed.
+    ///
+    /// Row IDs don't change, and deleted rows stay deleted.  Each
+    /// segment is replaced atomically, even across a crash.  Rows in
+    /// the active segment are compacted after it's sealed.
+    pub fn compact(&mut self) -> Result<u64> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        let mut reclaimed = 0;
+        let segments = self.shared.segments.read().unwrap().clone();
+        for old in segments {
+            if old.id() == self.active.id() || !old.has_garbage(self.first_row())? {
+                continue
+            }
+            old.write_compacted(self.first_row())?;
+
+            // Once the manifest records the compaction, it's finished
+            // even after a crash.
+            let mut manifest = self.shared.manifest();
+            manifest.compacting = Some(old.id());
+            manifest.store(&self.path)?;
+            segment::finish_compaction(old.dir(), old.id())?;
+            manifest.compacting = None;
+            manifest.store(&self.path)?;
+
+            let new = Segment::open(old.dir(), old.tier(), old.entry(), self.options.index_mode, self.stride, false, self.shared.log_cache.clone())?;
+            new.truncate_changes(self.next_row())?;
+            reclaimed += old.size()?.saturating_sub(new.size()?);
+            let mut segments = self.shared.segments.write().unwrap();
+            if let Some(segment) = segments.iter_mut().find(|segment| segment.id() == old.id()) {
+                *segment = Arc::new(new);
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Move the sealed segments last committed to longer ago than the
+    /// `cold_after` option to the cold storage directory, and return
+    /// how many were moved.  Moving a segment copies all its files, so
+    /// this is left to the caller, e.g. a periodic background task.
+    /// Readers keep reading the moved segments transparently.
+    pub fn move_cold(&mut self) -> Result<usize> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        self.shared.move_cold(&self.path, &self.options, self.active.id())
+    }
+
+    /// Give back the disk space of the dead ranges of the log files:
+    /// the payloads of deleted rows, and the parts before the first
+    /// row left in segments by `truncate_before`.  Returns the number
+    /// of bytes of disk space released, which only counts whole blocks
+    /// of the file system.  Dead ranges are found again on open, and
+    /// punching them again releases nothing more.
+    ///
+    /// The log files are not rewritten, and keep their sizes; released
+    /// ranges read back as zeros.  Holes are only punched on Linux.
+    /// Followers reading the released rows at the same time may read
+    /// zeros.
+    pub fn punch_holes(&mut self) -> Result<u64> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        let mut released = 0;
+        let segments = self.shared.segments.read().unwrap().clone();
+        for segment in segments {
+            released += segment.punch_holes()?;
+        }
+        Ok(released)
+    }
+
+    /// Number of dead bytes in the log files not released by
+    /// `punch_holes` yet.
+    pub fn dead_bytes(&self) -> u64 {
+        self.shared.segments.read().unwrap().iter().map(|segment| segment.dead_bytes()).sum()
+    }
+
+    /// Discard all rows before `row`, and reclaim the disk space of
+    /// the segments that only hold such rows.  Later rows keep their
+    /// row IDs.
+    ///
+    /// The active segment is never removed; its truncated rows are
+    /// reclaimed after it's sealed and later truncated again.  Fails
+    /// with `InvalidInput` if `row` is after the next row.
+    pub fn truncate_before(&mut self, row: RowId) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        if row > self.next_row() {
+            return Err(Error::new(ErrorKind::InvalidInput, "row is not committed yet"))
+        }
+        self.ops.get_mut().unwrap().checkpoint_before(&self.path, row)?;
+        self.shared.truncate_before(&self.path, self.active.id(), row)
+    }
+
+    /// Erase a committed row right away: the row is deleted, and its
+    /// payload is overwritten with zeros in the log file in place and
+    /// synced.  Reading the row fails with a `Deleted` error, also
+    /// after a later `truncate_after` which keeps the row.
+    ///
+    /// Every redaction is recorded in the audit file before the entry
+    /// is touched, and finished on the next open after a crash.  See
+    /// `redactions`.
+    pub fn redact(&mut self, row: RowId) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::from_raw_os_error(EROFS))
+        }
+        if row < self.first_row() || row >= self.next_row() {
+            return Err(Error::new(ErrorKind::InvalidInput, "row is not committed"))
+        }
+        self.audit.as_ref().unwrap().begin(row)?;
+        self.finish_redaction(row)
+    }
+
+    /// List all redactions recorded in the audit file, oldest first.
+    pub fn redactions(&self) -> Result<Vec<Redaction>> {
+        audit::load(&self.path)
+    }
+
+    fn finish_redaction(&self, row: RowId) -> Result<()> {
+        // A row truncated since may be punched out already, and its
+        // entry is not parsed anymore.
+        if let Some(segment) = self.shared.segment(row).filter(|_| row >= self.first_row()) {
+            self.ops.lock().unwrap().checkpoint_before(&self.path, row + 1)?;
+            segment.redact(row, self.next_row())?;
+        }
+        self.audit.as_ref().unwrap().finish(row)
+    }
+
+    /// Whether the active segment has reached the limits.
+    fn segment_full(&self) -> Result<bool> {
+        let rows = self.active.rows();
+        if rows == 0 {
+            return Ok(false)
+        }
+        if let Some(max_rows) = self.options.segment_rows {
+            if rows >= max_rows {
+                return Ok(true)
+            }
+        }
+        let max_size = self.options.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE);
+        Ok(self.active.size()? >= max_size)
+    }
+
+    /// Seal the active segment, and start a new one after it.
+    ///
+    /// The files of the new segment are created before it's added to
+    /// the manifest, so a crash in between only leaves empty files
+    /// behind, which are reused next time.
+    fn roll_over(&mut self) -> Result<()> {
+        let entry = Entry {
+            id: self.active.id() + 1,
+            base: self.active.end(),
+        };
+        let segment = Arc::new(Segment::open(&self.path, Tier::Hot, entry, self.options.index_mode, self.stride, false, self.shared.log_cache.clone())?);
+
+        let mut manifest = self.shared.manifest();
+        manifest.segments.push(entry);
+        manifest.store(&self.path)?;
+
+        self.shared.segments.write().unwrap().push(segment.clone());
+        self.active = segment;
         Ok(())
     }
 }
 
-impl Drop for Engine {
-    fn drop(&mut self) {
-        let _ = fs::remove_file(&self.lock_path);
+/// Cut the last of `segments` of a tail truncation recorded in
+/// `manifest` at `end`, remove the segments after it, and clear the
+/// record.  The generation is bumped again, for the followers which
+/// have seen the truncation in progress.  Returns the new manifest.
+fn finish_truncate_after(path: &Path, cold_dir: Option<&Path>, mut manifest: Manifest, segments: &[Arc<Segment>], end: RowId) -> Result<Manifest> {
+    let last = segments.last().unwrap();
+    last.truncate(end - last.base())?;
+    // Deletions and updates of the removed rows are dropped.
+    for segment in segments {
+        segment.truncate_changes_after(end)?;
     }
+    segment::remove_after(path, last.id())?;
+    if let Some(cold_dir) = cold_dir {
+        segment::remove_after(cold_dir, last.id())?;
+    }
+    operation::remove_checkpoints_after(path, end)?;
+    manifest.truncating = None;
+    manifest.generation += 1;
+    manifest.store(path)?;
+    Ok(manifest)
 }
 
-pub struct Transaction<'a> {
-    log_tx: LogTx<'a>,
-    index_tx: IndexTx<'a>,
-    info: &'a mut HashMap<Box<[u8]>, Box<[u8]>>,
-    info_updates: HashMap<Box<[u8]>, Box<[u8]>>,
+/// The committed part of the engine, shared by the engine and its
+/// readers.
+struct Shared {
+    /// All segments, in the order of their rows.  There is at least
+    /// one.
+    segments: RwLock<Vec<Arc<Segment>>>,
+    /// Rows before this one are truncated.  It's raised before the
+    /// truncated segments are removed.
+    first_row: AtomicU64,
+    /// The generation of the manifest, as last stored by the writer,
+    /// or seen by a follower on its last refresh.
+    generation: AtomicU64,
+    /// The generation after the last finished tail truncation or
+    /// reset, and the next row it left, for waiting readers and
+    /// subscriptions to rewind.
+    cut: Mutex<(u64, RowId)>,
+    /// How the log files of the segments are cached.
+    log_cache: LogCache,
+
+    /// Set when the engine is dropped, so that subscribers stop
+    /// waiting for new rows.
+    closed: Mutex<bool>,
+    /// Notified whenever new rows are committed.
+    committed: Condvar,
 }
 
-impl<'a> Transaction<'a> {
-    pub fn append(&mut self, entry: &[u8]) -> Result<RowId> {
-        Ok(self.index_tx.append(self.log_tx.append(entry)?))
+impl Shared {
+    fn last_segment(&self) -> Arc<Segment> {
+        self.segments.read().unwrap().last().unwrap().clone()
     }
 
-    pub fn put_info(&mut self, key: &[u8], value: &[u8]) {
-        self.info_updates.insert(Vec::from(key).into_boxed_slice(),
-                                 Vec::from(value).into_boxed_slice());
+    fn next_row(&self) -> RowId {
+        self.last_segment().end()
     }
 
-    pub fn commit(self) -> Result<()> {
-        self.log_tx.commit()?;
-        self.index_tx.commit()?;
-        for (k, v) in self.info_updates {
-            self.info.insert(k, v);
+    fn first_row(&self) -> RowId {
+        self.first_row.load(Ordering::SeqCst)
+    }
+
+    /// Discard all rows before `row`, and remove the segments except
+    /// `active` that only hold such rows.
+    fn truncate_before(&self, path: &Path, active: u64, row: RowId) -> Result<()> {
+        if row <= self.first_row() {
+            return Ok(())
+        }
+        let dropped: Vec<_> = self.segments.read().unwrap().iter()
+            .filter(|segment| segment.id() != active && segment.end() <= row)
+            .cloned()
+            .collect();
+
+        // Once the manifest is stored, the truncation survives a
+        // crash, and leftover files are removed on the next open.
+        let mut manifest = self.manifest();
+        manifest.first_row = row;
+        manifest.segments.retain(|entry| !dropped.iter().any(|segment| segment.id() == entry.id));
+        manifest.store(path)?;
+        self.first_row.store(row, Ordering::SeqCst);
+        self.segments.write().unwrap().retain(|segment| !dropped.iter().any(|s| s.id() == segment.id()));
+        for segment in dropped {
+            segment::remove(segment.dir(), segment.id())?;
+        }
+        if let Some(segment) = self.segment(row) {
+            segment.mark_truncated(row)?;
         }
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::tests::*;
+    /// The manifest describing the current segments.
+    fn manifest(&self) -> Manifest {
+        let segments = self.segments.read().unwrap();
+        Manifest {
+            first_row: self.first_row(),
+            segments: segments.iter().map(|s| s.entry()).collect(),
+            cold: segments.iter().filter(|s| s.tier() == Tier::Cold).map(|s| s.id()).collect(),
+            generation: self.generation.load(Ordering::SeqCst),
+            ..Manifest::default()
+        }
+    }
 
-    #[test]
+    /// Move the sealed segments last committed to before the
+    /// `cold_after` option to the cold storage directory, and return
+    /// how many were moved.
+    ///
+    /// A segment is copied before the manifest lists it as cold, and
+    /// removed from the repository directory afterwards, so a crash
+    /// leaves one complete copy behind, and the other is removed on the
+    /// next open.
+    fn move_cold(&self, path: &Path, options: &OpenOptions, active: u64) -> Result<usize> {
+        let (cold_dir, max_age) = match (&options.cold_dir, options.cold_after) {
+            (Some(dir), Some(age)) => (dir, age),
+            _ => return Ok(0),
+        };
+        let since = now_millis().saturating_sub(max_age.as_millis() as u64);
+        let segments = self.segments.read().unwrap().clone();
+        let mut moved = 0;
+        for old in segments {
+            if old.id() == active || old.tier() == Tier::Cold || old.last_commit()? > since {
+                continue
+            }
+            old.copy_to(cold_dir)?;
+            let new = Segment::open(cold_dir, Tier::Cold, old.entry(), options.index_mode, old.index.stride(), false, self.log_cache.clone())?;
+            new.load_changes(self.next_row())?;
+
+            let mut manifest = self.manifest();
+            manifest.cold.push(old.id());
+            manifest.store(path)?;
+            let mut segments = self.segments.write().unwrap();
+            if let Some(segment) = segments.iter_mut().find(|segment| segment.id() == old.id()) {
+                *segment = Arc::new(new);
+            }
+            drop(segments);
+            segment::remove(path, old.id())?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Find the first row to keep according to the retention limits.
+    fn retained_row(&self, options: &OpenOptions) -> Result<RowId> {
+        let segments = self.segments.read().unwrap().clone();
+        let next_row = segments.last().unwrap().end();
+        let mut row = self.first_row();
+
+        if let Some(max_rows) = options.retain_rows {
+            row = row.max(next_row.saturating_sub(max_rows));
+        }
+        if let Some(max_bytes) = options.retain_bytes {
+            let mut total = 0;
+            for segment in &segments {
+                total += segment.size()?;
+            }
+            for pair in segments.windows(2) {
+                if total <= max_bytes {
+                    break
+                }
+                total -= pair[0].size()?;
+                row = row.max(pair[1].base());
+            }
+        }
+        if let Some(max_age) = options.retain_age {
+            let since = now_millis().saturating_sub(max_age.as_millis() as u64);
+            let mut kept = next_row;
+            for segment in &segments {
+                if let Some(first) = segment.times.first_row_since(since)? {
+                    kept = segment.base() + first;
+                    break
+                }
+            }
+            row = row.max(kept);
+        }
+        Ok(row)
+    }
+
+    /// Apply the operations to the committed rows they haven't applied
+    /// yet.
+    fn apply_operations(&self, path: &Path, ops: &mut Operations) -> Result<()> {
+        let next_row = self.next_row();
+        // Rows may have been removed since the operations applied them.
+        ops.rewind(path, next_row)?;
+        ops.skip_to(self.first_row());
+        let start = match ops.next_row() {
+            Some(row) => row,
+            None => return Ok(()),
+        };
+        for row in start..next_row {
+            match self.get(row) {
+                Ok(Some(entry)) => ops.apply(row, &entry)?,
+                Ok(None) => break,
+                Err(ref e) if Deleted::from_io(e).is_some() => ops.skip_to(row + 1),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the segment `row` belongs to.
+    fn segment(&self, row: RowId) -> Option<Arc<Segment>> {
+        let segments = self.segments.read().unwrap();
+        match segments.partition_point(|segment| segment.base() <= row) {
+            0 => None,
+            i => Some(segments[i - 1].clone()),
+        }
+    }
+
+    /// The generation after the last tail truncation or reset, and the
+    /// next row it left.
+    fn cut(&self) -> (u64, RowId) {
+        *self.cut.lock().unwrap()
+    }
+
+    /// Record a finished tail truncation or reset, which left
+    /// `next_row` as the next row in `generation`, and wake up all
+    /// subscribers.
+    fn cut_tail(&self, generation: u64, next_row: RowId) {
+        *self.cut.lock().unwrap() = (generation, next_row);
+        self.notify();
+    }
+
+    /// Wake up all subscribers.
+    fn notify(&self) {
+        let _closed = self.closed.lock().unwrap();
+        self.committed.notify_all();
+    }
+
+    /// Mark the engine as closed, and wake up all subscribers.
+    fn close(&self) {
+        *self.closed.lock().unwrap() = true;
+        self.committed.notify_all();
+    }
+
+    /// Wait until `row` is committed, rows are removed from the end
+    /// after `generation`, the engine is closed, or the deadline is
+    /// reached.  Returns true in the first two cases.
+    fn wait_for(&self, row: RowId, generation: u64, deadline: Option<Instant>) -> bool {
+        let mut closed = self.closed.lock().unwrap();
+        loop {
+            if row < self.next_row() || self.cut().0 != generation {
+                return true
+            }
+            if *closed {
+                return false
+            }
+            closed = match deadline {
+                None => self.committed.wait(closed).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false
+                    }
+                    self.committed.wait_timeout(closed, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Find the segment of a committed row.  Fails if the row is
+    /// truncated or deleted.
+    fn locate(&self, row: RowId) -> Result<Option<Arc<Segment>>> {
+        // The watermark is checked after looking up the segment, since
+        // it's raised before segments are removed.
+        let segment = self.segment(row);
+        let first_row = self.first_row();
+        if row < first_row {
+            return Err(Error::new(ErrorKind::NotFound, Truncated { row, first_row }))
+        }
+        let segment = match segment {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        if segment.is_deleted(row) {
+            return Err(Error::new(ErrorKind::NotFound, Deleted { row }))
+        }
+        Ok(Some(segment))
+    }
+
+    fn get(&self, row: RowId) -> Result<Option<Box<[u8]>>> {
+        self.get_version(row, None)
+    }
+
+    fn prefetch(&self, rows: Range<RowId>) -> Result<()> {
+        let start = rows.start.max(self.first_row());
+        let end = rows.end.min(self.next_row());
+        let segments: Vec<_> = self.segments.read().unwrap().iter()
+            .filter(|segment| segment.base() < end && segment.end() > start)
+            .cloned()
+            .collect();
+        for segment in segments {
+            segment.prefetch(start..end)?;
+        }
+        Ok(())
+    }
+
+    /// Get a version of a committed row, or the latest one if
+    /// `version` is `None`.
+    fn get_version(&self, row: RowId, version: Option<usize>) -> Result<Option<Box<[u8]>>> {
+        let segment = match self.locate(row)? {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        match segment.locate_version(row, version)? {
+            Some((log, offset)) => {
+                let len = log.entry_len(offset)?;
+                let mut buf = vec![0; len];
+                log.read_entry_at(offset, 0, &mut buf[..])?;
+                Ok(Some(buf.into_boxed_slice()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn entry_len(&self, row: RowId) -> Result<Option<usize>> {
+        let segment = match self.locate(row)? {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        match segment.locate_version(row, None)? {
+            Some((log, offset)) => Ok(Some(log.entry_len(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_range(&self, row: RowId, start: usize, len: usize) -> Result<Option<Box<[u8]>>> {
+        let segment = match self.locate(row)? {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        let (log, offset) = match segment.locate_version(row, None)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let entry_len = log.entry_len(offset)?;
+        match start.checked_add(len) {
+            Some(end) if end <= entry_len => (),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "range out of entry bounds")),
+        }
+        let mut buf = vec![0; len];
+        log.read_entry_at(offset, start, &mut buf[..])?;
+        Ok(Some(buf.into_boxed_slice()))
+    }
+}
+
+/// The error of reading a row discarded by `Engine::truncate_before`.
+///
+/// It comes wrapped in an `io::Error` of kind `NotFound`.  Use
+/// `Truncated::from_io` to tell it from other errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Truncated {
+    /// The requested row.
+    pub row: RowId,
+    /// The first row which is not truncated.
+    pub first_row: RowId,
+}
+
+impl Truncated {
+    /// Get the `Truncated` error wrapped in `err`, if any.
+    pub fn from_io(err: &Error) -> Option<Truncated> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Truncated>())
+            .copied()
+    }
+}
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "row {} is truncated, the first row is {}", self.row, self.first_row)
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// The error a subscription yields when the rows it is about to yield
+/// are removed by `Engine::truncate_after` or `Engine::reset`.
+///
+/// It comes wrapped in an `io::Error` of kind `NotFound`.  Use
+/// `Rewound::from_io` to tell it from other errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rewound {
+    /// The row the subscription was about to yield.
+    pub row: RowId,
+    /// The row it goes on from.
+    pub next_row: RowId,
+}
+
+impl Rewound {
+    /// Get the `Rewound` error wrapped in `err`, if any.
+    pub fn from_io(err: &Error) -> Option<Rewound> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Rewound>())
+            .copied()
+    }
+}
+
+impl fmt::Display for Rewound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "row {} is removed, going on from row {}", self.row, self.next_row)
+    }
+}
+
+impl std::error::Error for Rewound {}
+
+/// The error of reading a row deleted by `Transaction::delete`.
+///
+/// It comes wrapped in an `io::Error` of kind `NotFound`.  Use
+/// `Deleted::from_io` to tell it from other errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deleted {
+    /// The requested row.
+    pub row: RowId,
+}
+
+impl Deleted {
+    /// Get the `Deleted` error wrapped in `err`, if any.
+    pub fn from_io(err: &Error) -> Option<Deleted> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Deleted>())
+            .copied()
+    }
+}
+
+impl fmt::Display for Deleted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "row {} is deleted", self.row)
+    }
+}
+
+impl std::error::Error for Deleted {}
+
+/// A read-only handle to the committed rows of an engine.
+///
+/// Readers are cheap to clone and can be sent to other threads.
+/// New rows become visible to readers atomically when their
+/// transaction commits.
+#[derive(Clone)]
+pub struct Reader {
+    shared: Arc<Shared>,
+}
+
+impl Reader {
+    /// Get a committed entry from the log file.
+    pub fn get(&self, row: RowId) -> Result<Option<Box<[u8]>>> {
+        self.shared.get(row)
+    }
+
+    /// Get a version of a committed entry.  See
+    /// `Engine::get_version`.
+    pub fn get_version(&self, row: RowId, version: usize) -> Result<Option<Box<[u8]>>> {
+        self.shared.get_version(row, Some(version))
+    }
+
+    /// Get the length of a committed entry.
+    pub fn entry_len(&self, row: RowId) -> Result<Option<usize>> {
+        self.shared.entry_len(row)
+    }
+
+    /// Read part of a committed entry.  See `Engine::read_range`.
+    pub fn read_range(&self, row: RowId, start: usize, len: usize) -> Result<Option<Box<[u8]>>> {
+        self.shared.read_range(row, start, len)
+    }
+
+    /// Get the next row ID.
+    pub fn next_row(&self) -> RowId {
+        self.shared.next_row()
+    }
+
+    /// Get the first row which is not truncated.
+    pub fn first_row(&self) -> RowId {
+        self.shared.first_row()
+    }
+
+    /// Number of committed entries, not counting truncated ones.
+    pub fn count(&self) -> usize {
+        (self.next_row() - self.first_row()) as usize
+    }
+
+    /// Follow the log from `from_row` on.  See `Engine::subscribe`.
+    pub fn subscribe(&self, from_row: RowId) -> Subscription {
+        Subscription {
+            reader: self.clone(),
+            next: from_row,
+            generation: self.shared.cut().0,
+        }
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        if !self.is_read_only() {
+            if let Ok(ops) = self.ops.get_mut() {
+                let _ = ops.checkpoint(&self.path, 1);
+            }
+        }
+        self.shared.close();
+        if let Some(lock_path) = &self.lock_path {
+            let _ = fs::remove_file(lock_path);
+        }
+    }
+}
+
+/// A subscription to newly committed rows.
+///
+/// As an iterator, it blocks until the next row is committed, and
+/// ends when the engine is dropped and all committed rows are
+/// consumed.  Deleted rows are skipped.
+pub struct Subscription {
+    reader: Reader,
+    next: RowId,
+    /// The generation after the last tail truncation or reset seen.
+    generation: u64,
+}
+
+impl Subscription {
+    /// The row ID to be yielded next.
+    pub fn next_row(&self) -> RowId {
+        self.next
+    }
+
+    /// Get the next row if it is already committed, without waiting.
+    pub fn try_next(&mut self) -> Result<Option<(RowId, Box<[u8]>)>> {
+        self.next_timeout(Duration::from_secs(0))
+    }
+
+    /// Get the next row, waiting at most `timeout` for it to be
+    /// committed.  Returns `None` on timeout.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<(RowId, Box<[u8]>)>> {
+        let deadline = Instant::now() + timeout;
+        while self.reader.shared.wait_for(self.next, self.generation, Some(deadline)) {
+            if let Some(item) = self.take() {
+                return item.map(Some)
+            }
+        }
+        Ok(None)
+    }
+
+    /// Take the next row, which must be committed, unless rows were
+    /// removed from the end.  Deleted rows are skipped, and give
+    /// `None`.  Truncated rows are skipped too, but give the error.
+    /// Removed rows give a `Rewound` error, once the removal is
+    /// finished.
+    fn take(&mut self) -> Option<Result<(RowId, Box<[u8]>)>> {
+        let (generation, end) = self.reader.shared.cut();
+        if generation != self.generation {
+            self.generation = generation;
+            if self.next > end {
+                let row = self.next;
+                self.next = end;
+                return Some(Err(Error::new(ErrorKind::NotFound, Rewound { row, next_row: end })))
+            }
+        }
+        let row = self.next;
+        match self.reader.get(row) {
+            Ok(Some(entry)) => {
+                self.next += 1;
+                Some(Ok((row, entry)))
+            }
+            // Removed from the end by a removal which is not finished
+            // yet.  Waiting again ends when it is.
+            Ok(None) => None,
+            Err(ref e) if Deleted::from_io(e).is_some() => {
+                self.next += 1;
+                None
+            }
+            Err(e) => {
+                if let Some(truncated) = Truncated::from_io(&e) {
+                    self.next = truncated.first_row;
+                }
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<(RowId, Box<[u8]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.reader.shared.wait_for(self.next, self.generation, None) {
+            if let Some(item) = self.take() {
+                return Some(item)
+            }
+        }
+        None
+    }
+}
+
+pub struct Transaction<'a> {
+    shared: &'a Shared,
+    path: &'a Path,
+    options: &'a OpenOptions,
+    /// The segment being written to.
+    active: &'a Segment,
+    /// Number of rows of the active segment before the transaction.
+    start: RowId,
+    appended: RowId,
+    /// Rows to delete, relative to the base rows of their segments.
+    deletes: Vec<(Arc<Segment>, RowId)>,
+    /// New versions of rows, relative to the base rows of their
+    /// segments.
+    updates: Vec<(Arc<Segment>, RowId, Box<[u8]>)>,
+    log_tx: LogTx<'a>,
+    index_tx: IndexTx<'a>,
+    ops: &'a Mutex<Operations>,
+    hooks: &'a mut Hooks,
+    /// Copies of the appended entries, kept for pre-commit hooks only.
+    entries: Vec<Box<[u8]>>,
+    info: &'a mut HashMap<Box<[u8]>, Box<[u8]>>,
+    info_updates: HashMap<Box<[u8]>, Box<[u8]>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn append(&mut self, entry: &[u8]) -> Result<RowId> {
+        let row = self.index_tx.append(self.log_tx.append(entry)?);
+        self.appended += 1;
+        if self.hooks.wants_entries() {
+            self.entries.push(Box::from(entry));
+        }
+        Ok(self.active.base() + row)
+    }
+
+    /// Delete a committed row.  Once the transaction commits, reading
+    /// the row fails with a `Deleted` error.  The payload stays on
+    /// disk until `Engine::compact`.
+    ///
+    /// Fails with `InvalidInput` if the row is not committed before
+    /// the transaction, or truncated.
+    pub fn delete(&mut self, row: RowId) -> Result<()> {
+        if row < self.shared.first_row() || row >= self.active.base() + self.start {
+            return Err(Error::new(ErrorKind::InvalidInput, "row is not committed"))
+        }
+        let segment = self.shared.segment(row).unwrap();
+        let local = row - segment.base();
+        self.deletes.push((segment, local));
+        Ok(())
+    }
+
+    /// Replace a committed row with a new version.  Once the
+    /// transaction commits, reading the row gives the new version,
+    /// and the older ones are still available from
+    /// `Engine::get_version` until `Engine::compact`.
+    ///
+    /// Fails with `InvalidInput` if the row is not committed before
+    /// the transaction, or truncated, and with a `Deleted` error if it
+    /// is deleted.
+    pub fn update(&mut self, row: RowId, entry: &[u8]) -> Result<()> {
+        if row < self.shared.first_row() || row >= self.active.base() + self.start {
+            return Err(Error::new(ErrorKind::InvalidInput, "row is not committed"))
+        }
+        let segment = self.shared.segment(row).unwrap();
+        if segment.is_deleted(row) {
+            return Err(Error::new(ErrorKind::NotFound, Deleted { row }))
+        }
+        let local = row - segment.base();
+        self.updates.push((segment, local, Box::from(entry)));
+        Ok(())
+    }
+
+    pub fn put_info(&mut self, key: &[u8], value: &[u8]) {
+        self.info_updates.insert(Vec::from(key).into_boxed_slice(),
+                                 Vec::from(value).into_boxed_slice());
+    }
+
+    /// Run the pre-commit hooks, commit the transaction, run the
+    /// post-commit hooks, and then apply the operations and enforce
+    /// the retention limits.
+    ///
+    /// If a pre-commit hook fails, the transaction is rolled back.
+    /// Failures to apply the operations or to enforce the retention
+    /// limits don't fail the commit, and are passed to the error hooks
+    /// instead.
+    pub fn commit(self) -> Result<()> {
+        let first_row = self.active.base() + self.start;
+        let pending_deletes: Vec<_> = self.deletes.iter().map(|(segment, row)| segment.base() + row).collect();
+        let pending_updates: Vec<_> = self.updates.iter().map(|(segment, row, entry)| (segment.base() + row, &entry[..])).collect();
+        self.hooks.pre_commit(&PendingCommit {
+            first_row,
+            entries: &self.entries,
+            deletes: &pending_deletes,
+            updates: &pending_updates,
+            info_updates: &self.info_updates,
+        })?;
+
+        // Operations which applied the rows about to change keep them
+        // as they were, even when they're replayed.
+        let changed = self.deletes.iter().map(|(segment, row)| segment.base() + row)
+            .chain(self.updates.iter().map(|(segment, row, _)| segment.base() + row))
+            .min();
+        if let Some(row) = changed {
+            self.ops.lock().unwrap().checkpoint_before(self.path, row + 1)?;
+        }
+
+        // Tombstones and new versions are written first, but take
+        // effect only if the rest of the transaction commits.  They're
+        // cut off again if it doesn't.
+        let next_row = first_row + self.appended;
+        let mut written = Written::default();
+        let mut deletes = self.deletes;
+        deletes.sort_by_key(|(segment, row)| (segment.id(), *row));
+        for group in deletes.chunk_by(|a, b| a.0.id() == b.0.id()) {
+            let rows: Vec<_> = group.iter().map(|(_, row)| *row).collect();
+            let segment = &group[0].0;
+            written.tombstones.push((segment.clone(), segment.tombstones.size()?));
+            segment.tombstones.write(&rows, next_row)?;
+        }
+        // Updates of the same row keep their order.
+        let mut updates = self.updates;
+        updates.sort_by_key(|(segment, _, _)| segment.id());
+        for group in updates.chunk_by(|a, b| a.0.id() == b.0.id()) {
+            let versions: Vec<_> = group.iter().map(|(_, row, entry)| (*row, &entry[..])).collect();
+            let segment = &group[0].0;
+            written.versions.push((segment.clone(), segment.versions.lens()?));
+            segment.versions.write(&versions, next_row)?;
+        }
+
+        self.log_tx.commit()?;
+        self.index_tx.commit()?;
+        written.committed = true;
+        // The transaction is durable, so what fails from here on doesn't
+        // fail the commit.  A missing commit time only makes retention
+        // by age see the rows as committed with the previous ones.
+        if self.appended > 0 {
+            if let Err(e) = self.active.times.record_commit(self.start) {
+                self.hooks.error(&e);
+            }
+        }
+        // Changes not loaded now are loaded with the next ones of their
+        // segments.
+        let loaded = deletes.chunk_by(|a, b| a.0.id() == b.0.id())
+            .try_for_each(|group| {
+                let rows: Vec<_> = group.iter().map(|(_, row)| *row).collect();
+                group[0].0.tombstones.load(next_row)?;
+                group[0].0.mark_deleted(&rows)
+            })
+            .and_then(|()| updates.chunk_by(|a, b| a.0.id() == b.0.id())
+                .try_for_each(|group| group[0].0.versions.load(next_row)));
+        if let Err(e) = loaded {
+            self.hooks.error(&e);
+        }
+        for (k, v) in self.info_updates {
+            self.info.insert(k, v);
+        }
+        self.shared.notify();
+        self.hooks.post_commit(first_row..next_row);
+
+        let (shared, path, active) = (self.shared, self.path, self.active.id());
+        let checkpoint_rows = self.options.checkpoint_rows.unwrap_or(DEFAULT_CHECKPOINT_ROWS);
+        let mut ops = self.ops.lock().unwrap();
+        let applied = shared.apply_operations(path, &mut ops)
+            .and_then(|()| ops.checkpoint(path, checkpoint_rows));
+        if let Err(e) = applied {
+            self.hooks.error(&e);
+        }
+        let retained = shared.retained_row(self.options)
+            .and_then(|row| ops.checkpoint_before(path, row).map(|()| row));
+        drop(ops);
+        let retained = retained.and_then(|row| shared.truncate_before(path, active, row));
+        if let Err(e) = retained {
+            self.hooks.error(&e);
+        }
+        Ok(())
+    }
+}
+
+/// What a transaction wrote ahead of its commit, with the lengths
+/// of the files before.  Unless the transaction commits, the files
+/// are cut back to them when this is dropped.
+#[derive(Default)]
+struct Written {
+    tombstones: Vec<(Arc<Segment>, u64)>,
+    versions: Vec<(Arc<Segment>, (u64, u64))>,
+    committed: bool,
+}
+
+impl Drop for Written {
+    fn drop(&mut self) {
+        if self.committed {
+            return
+        }
+        for (segment, len) in &self.tombstones {
+            let _ = segment.tombstones.rollback(*len);
+        }
+        for (segment, lens) in &self.versions {
+            let _ = segment.versions.rollback(*lens);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::HEADER_SIZE;
+    use crate::hole::DeadRanges;
+    use crate::tests::*;
+
+    #[test]
     fn open() {
         ensure_dir_nonexistent("dbopen");
         {
@@ -182,6 +1792,1191 @@ mod tests {
         fs::remove_dir_all("dbopen2").unwrap();
     }
 
+    #[test]
+    fn concurrent_readers() {
+        use std::thread;
+
+        ensure_dir_nonexistent("dbreaders");
+        let mut engine = Engine::open("dbreaders").unwrap();
+        let mut tx = engine.transaction().unwrap();
+        for i in 0..100u64 {
+            tx.append(&i.to_le_bytes()).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let reader = engine.reader();
+        let mut tx = engine.transaction().unwrap();
+        for i in 100..200u64 {
+            tx.append(&i.to_le_bytes()).unwrap();
+        }
+
+        // Readers see committed rows while the transaction is open.
+        let handles: Vec<_> = (0..4).map(|_| {
+            let reader = reader.clone();
+            thread::spawn(move || {
+                assert_eq!(reader.count(), 100);
+                for i in 0..100u64 {
+                    assert_eq!(reader.get(i).unwrap().unwrap().as_ref(), &i.to_le_bytes());
+                }
+                assert!(reader.get(100).unwrap().is_none());
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        tx.commit().unwrap();
+        assert_eq!(reader.count(), 200);
+        assert_eq!(reader.get(150).unwrap().unwrap().as_ref(), &150u64.to_le_bytes());
+
+        drop(engine);
+        fs::remove_dir_all("dbreaders").unwrap();
+    }
+
+    #[test]
+    fn cached_reads_during_commits() {
+        use std::thread;
+
+        fn entry(i: u64) -> Vec<u8> {
+            format!("{:0100}", i).into_bytes()
+        }
+
+        ensure_dir_nonexistent("dbcachedreads");
+        let mut engine = OpenOptions::new().cache_size(16 * 1024).open("dbcachedreads").unwrap();
+        let mut tx = engine.transaction().unwrap();
+        tx.append(&entry(0)).unwrap();
+        tx.commit().unwrap();
+
+        // Cached reads of committed rows stay right while the log file
+        // is appended to.
+        let reader = engine.reader();
+        let handles: Vec<_> = (0..4).map(|_| {
+            let reader = reader.clone();
+            thread::spawn(move || {
+                while reader.count() < 2000 {
+                    let count = reader.count() as u64;
+                    for i in count.saturating_sub(50)..count {
+                        assert_eq!(reader.get(i).unwrap().unwrap().as_ref(), entry(i).as_slice());
+                    }
+                }
+            })
+        }).collect();
+        for i in 1..2000 {
+            let mut tx = engine.transaction().unwrap();
+            tx.append(&entry(i)).unwrap();
+            tx.commit().unwrap();
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        drop(engine);
+        fs::remove_dir_all("dbcachedreads").unwrap();
+    }
+
+    #[test]
+    fn subscribe() {
+        use std::thread;
+
+        ensure_dir_nonexistent("dbsubscribe");
+        let mut engine = Engine::open("dbsubscribe").unwrap();
+        let mut tx = engine.transaction().unwrap();
+        tx.append(b"0").unwrap();
+        tx.commit().unwrap();
+
+        let mut sub = engine.subscribe(0);
+        assert_eq!(sub.try_next().unwrap().unwrap(), (0, b"0".to_vec().into_boxed_slice()));
+        assert!(sub.try_next().unwrap().is_none());
+        assert!(sub.next_timeout(Duration::from_millis(10)).unwrap().is_none());
+
+        let follower = thread::spawn(move || {
+            sub.map(|r| r.unwrap()).collect::<Vec<_>>()
+        });
+        for i in 1..10u64 {
+            let mut tx = engine.transaction().unwrap();
+            tx.append(i.to_string().as_bytes()).unwrap();
+            tx.commit().unwrap();
+        }
+        drop(engine);
+
+        let rows = follower.join().unwrap();
+        assert_eq!(rows.len(), 9);
+        for (i, (row, entry)) in rows.into_iter().enumerate() {
+            assert_eq!(row, i as RowId + 1);
+            assert_eq!(entry.as_ref(), (i + 1).to_string().as_bytes());
+        }
+
+        // A subscription behind truncated rows reports them once, and
+        // catches up from the first row.
+        let mut engine = Engine::open("dbsubscribe").unwrap();
+        let mut sub = engine.subscribe(2);
+        engine.truncate_before(5).unwrap();
+        let err = sub.try_next().unwrap_err();
+        assert_eq!(Truncated::from_io(&err), Some(Truncated { row: 2, first_row: 5 }));
+        assert_eq!(sub.try_next().unwrap().unwrap(), (5, b"5".to_vec().into_boxed_slice()));
+
+        // So does one past rows removed from the end, and it goes on
+        // with the rows written there again.
+        sub.try_next().unwrap().unwrap();
+        sub.try_next().unwrap().unwrap();
+        engine.truncate_after(5).unwrap();
+        let mut tx = engine.transaction().unwrap();
+        tx.append(b"new").unwrap();
+        tx.commit().unwrap();
+        let err = sub.try_next().unwrap_err();
+        assert_eq!(Rewound::from_io(&err), Some(Rewound { row: 8, next_row: 6 }));
+        assert_eq!(sub.try_next().unwrap().unwrap(), (6, b"new".to_vec().into_boxed_slice()));
+
+        // A waiting subscription wakes up on a reset.
+        let mut sub = engine.subscribe(7);
+        let waiter = thread::spawn(move || sub.next().unwrap().unwrap_err());
+        thread::sleep(Duration::from_millis(20));
+        engine.reset().unwrap();
+        let err = waiter.join().unwrap();
+        assert_eq!(Rewound::from_io(&err), Some(Rewound { row: 7, next_row: 0 }));
+        drop(engine);
+
+        fs::remove_dir_all("dbsubscribe").unwrap();
+    }
+
+    #[test]
+    fn read_only_follower() {
+        use std::thread;
+
+        ensure_dir_nonexistent("dbfollower");
+        assert!(Engine::open_read_only("dbfollower").is_err());
+
+        let mut engine = Engine::open("dbfollower").unwrap();
+        let mut tx = engine.transaction().unwrap();
+        tx.append(b"first").unwrap();
+        tx.commit().unwrap();
+
+        let mut follower = Engine::open_read_only("dbfollower").unwrap();
+        assert!(follower.is_read_only());
+        assert!(follower.transaction().is_err());
+        assert_eq!(follower.count(), 1);
+        assert_eq!(follower.wait_for_rows(Duration::from_millis(10)).unwrap(), 0);
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            let mut tx = engine.transaction().unwrap();
+            tx.append(b"second").unwrap();
+            tx.commit().unwrap();
+            engine
+        });
+        assert_eq!(follower.wait_for_rows(Duration::from_secs(10)).unwrap(), 1);
+        assert_eq!(follower.get(1).unwrap().unwrap().as_ref(), b"second");
+
+        // The follower leaves the writer's lock alone.
+        let engine = writer.join().unwrap();
+        drop(follower);
+        assert!(Engine::open("dbfollower").is_err());
+        drop(engine);
+
+        fs::remove_dir_all("dbfollower").unwrap();
+    }
+
+    #[test]
+    fn partial_reads() {
+        ensure_dir_nonexistent("dbpartial");
+        let mut engine = Engine::open("dbpartial").unwrap();
+        let mut tx = engine.transaction().unwrap();
+        let row = tx.append(b"header:payload").unwrap();
+        let empty = tx.append(b"").unwrap();
+        tx.commit().unwrap();
+
+        assert_eq!(engine.entry_len(row).unwrap(), Some(14));
+        assert_eq!(engine.entry_len(empty).unwrap(), Some(0));
+        assert_eq!(engine.entry_len(empty + 1).unwrap(), None);
+        assert_eq!(engine.read_range(row, 0, 6).unwrap().unwrap().as_ref(), b"header");
+        assert_eq!(engine.read_range(row, 7, 7).unwrap().unwrap().as_ref(), b"payload");
+        assert_eq!(engine.read_range(row, 14, 0).unwrap().unwrap().as_ref(), b"");
+        assert!(engine.read_range(row, 7, 8).is_err());
+        assert!(engine.read_range(empty + 1, 0, 0).unwrap().is_none());
+
+        // Prefetching ignores rows which don't exist.
+        engine.prefetch(0..10).unwrap();
+        engine.prefetch(5..10).unwrap();
+        assert_eq!(engine.get(row).unwrap().unwrap().as_ref(), b"header:payload");
+
+        drop(engine);
+        fs::remove_dir_all("dbpartial").unwrap();
+    }
+
+    #[test]
+    fn paged_index() {
+        ensure_dir_nonexistent("dbpaged");
+        let mut options = OpenOptions::new();
+        options.index_mode(IndexMode::Paged { budget: 4096 });
+
+        let mut engine = options.open("dbpaged").unwrap();
+        let mut tx = engine.transaction().unwrap();
+        for i in 0..10_000u64 {
+            tx.append(&i.to_le_bytes()).unwrap();
+        }
+        tx.commit().unwrap();
+        drop(engine);
+
+        let engine = options.open("dbpaged").unwrap();
+        assert_eq!(engine.count(), 10_000);
+        for i in (0..10_000u64).step_by(7) {
+            assert_eq!(engine.get(i).unwrap().unwrap().as_ref(), &i.to_le_bytes());
+        }
+
+        drop(engine);
+        fs::remove_dir_all("dbpaged").unwrap();
+    }
+
+    #[test]
+    fn sparse_index() {
+        ensure_dir_nonexistent("dbsparse");
+        {
+            let mut engine = OpenOptions::new().sparse(16).open("dbsparse").unwrap();
+            for batch in 0..10u64 {
+                let mut tx = engine.transaction().unwrap();
+                for i in 0..7 {
+                    tx.append(&(batch * 7 + i).to_le_bytes()).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+
+            // Rolled back rows leave no trace.
+            let mut tx = engine.transaction().unwrap();
+            tx.append(b"rolled back").unwrap();
+            drop(tx);
+        }
+        assert_eq!(fs::metadata("dbsparse/IDX0").unwrap().len(), 5 * 8);
+
+        // The stride is remembered, and can't be changed.
+        assert!(OpenOptions::new().sparse(2).open("dbsparse").is_err());
+        let engine = Engine::open("dbsparse").unwrap();
+        assert_eq!(engine.count(), 70);
+        for i in 0..70u64 {
+            assert_eq!(engine.get(i).unwrap().unwrap().as_ref(), &i.to_le_bytes());
+        }
+        assert_eq!(engine.read_range(69, 1, 3).unwrap().unwrap().as_ref(), &69u64.to_le_bytes()[1..4]);
+        assert!(engine.get(70).unwrap().is_none());
+
+        drop(engine);
+        fs::remove_dir_all("dbsparse").unwrap();
+    }
+
+    #[test]
+    fn row_id_migration() {
+        ensure_dir_nonexistent("dbrowid");
+        {
+            let mut engine = Engine::open("dbrowid").unwrap();
+            let mut tx = engine.transaction().unwrap();
+            tx.append(b"old").unwrap();
+            tx.commit().unwrap();
+        }
+
+        // Pretend the repository is written with 32-bit row IDs.
+        let mut meta = Meta::load("dbrowid").unwrap();
+        meta.set("row_id_bits", 32);
+        meta.store("dbrowid").unwrap();
+        {
+            let engine = Engine::open("dbrowid").unwrap();
+            assert_eq!(engine.get(0).unwrap().unwrap().as_ref(), b"old");
+        }
+        let meta = Meta::load("dbrowid").unwrap();
+        assert_eq!(meta.get::<usize>("row_id_bits").unwrap(), Some(64));
+
+        let mut meta = Meta::load("dbrowid").unwrap();
+        meta.set("row_id_bits", 128);
+        meta.store("dbrowid").unwrap();
+        assert!(Engine::open("dbrowid").is_err());
+
+        fs::remove_dir_all("dbrowid").unwrap();
+    }
+
+    #[test]
+    fn segments() {
+        ensure_dir_nonexistent("dbsegments");
+        let mut options = OpenOptions::new();
+        options.segment_rows(10);
+        {
+            let mut engine = options.open("dbsegments").unwrap();
+            let follower = Engine::open_read_only("dbsegments").unwrap();
+            for batch in 0..5u64 {
+                let mut tx = engine.transaction().unwrap();
+                for i in 0..7 {
+                    tx.append(&(batch * 7 + i).to_le_bytes()).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+            // 14, 14 and 7 rows.
+            assert_eq!(engine.segment_count(), 3);
+            assert_eq!(follower.refresh().unwrap(), 35);
+            assert_eq!(follower.get(20).unwrap().unwrap().as_ref(), &20u64.to_le_bytes());
+        }
+        assert_eq!(Manifest::load("dbsegments").unwrap().segments, vec![
+            Entry { id: 0, base: 0 },
+            Entry { id: 1, base: 14 },
+            Entry { id: 2, base: 28 },
+        ]);
+
+        // The limits only apply to new segments.
+        let mut engine = OpenOptions::new().segment_size(64).open("dbsegments").unwrap();
+        assert_eq!(engine.count(), 35);
+        for i in 0..35u64 {
+            assert_eq!(engine.get(i).unwrap().unwrap().as_ref(), &i.to_le_bytes());
+        }
+        assert_eq!(engine.read_range(30, 0, 1).unwrap().unwrap().as_ref(), &[30]);
+        engine.prefetch(10..30).unwrap();
+        for i in 10..30u64 {
+            assert_eq!(engine.get(i).unwrap().unwrap().as_ref(), &i.to_le_bytes());
+        }
+        let mut tx = engine.transaction().unwrap();
+        assert_eq!(tx.append(b"new").unwrap(), 35);
+        tx.commit().unwrap();
+        assert_eq!(engine.segment_count(), 4);
+        assert_eq!(engine.get(35).unwrap().unwrap().as_ref(), b"new");
+        assert!(engine.get(36).unwrap().is_none());
+
+        let sub = engine.subscribe(12);
+        drop(engine);
+        assert_eq!(sub.map(|r| r.unwrap().0).collect::<Vec<_>>(), (12..36).collect::<Vec<_>>());
+
+        fs::remove_dir_all("dbsegments").unwrap();
+    }
+
+    #[test]
+    fn truncate_before() {
+        ensure_dir_nonexistent("dbtruncate");
+        let mut options = OpenOptions::new();
+        options.segment_rows(10);
+        {
+            let mut engine = options.open("dbtruncate").unwrap();
+            let follower = Engine::open_read_only("dbtruncate").unwrap();
+            for i in 0..35u64 {
+                let mut tx = engine.transaction().unwrap();
+                tx.append(&i.to_le_bytes()).unwrap();
+                tx.commit().unwrap();
+            }
+            assert_eq!(engine.segment_count(), 4);
+            assert!(engine.truncate_before(36).is_err());
+
+            engine.truncate_before(25).unwrap();
+            assert_eq!(engine.first_row(), 25);
+            assert_eq!(engine.count(), 10);
+            assert_eq!(engine.segment_count(), 2);
+            assert!(!Path::new("dbtruncate/LOG1").exists());
+            assert!(Path::new("dbtruncate/LOG2").exists());
+
+            let err = engine.get(24).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+            assert_eq!(Truncated::from_io(&err), Some(Truncated { row: 24, first_row: 25 }));
+            assert_eq!(engine.get(25).unwrap().unwrap().as_ref(), &25u64.to_le_bytes());
+
+            // Truncating again is a no-op.
+            engine.truncate_before(3).unwrap();
+            assert_eq!(engine.first_row(), 25);
+
+            follower.refresh().unwrap();
+            assert_eq!(follower.first_row(), 25);
+            assert!(Truncated::from_io(&follower.get(0).unwrap_err()).is_some());
+            assert_eq!(follower.get(34).unwrap().unwrap().as_ref(), &34u64.to_le_bytes());
+
+            // The active segment is kept, even if all of its rows are
+            // truncated.
+            engine.truncate_before(35).unwrap();
+            assert_eq!(engine.count(), 0);
+            assert_eq!(engine.segment_count(), 1);
+            assert_eq!(engine.next_row(), 35);
+        }
+
+        let mut engine = Engine::open("dbtruncate").unwrap();
+        assert_eq!(engine.first_row(), 35);
+        assert!(engine.get(34).is_err());
+        let mut tx = engine.transaction().unwrap();
+        assert_eq!(tx.append(b"next").unwrap(), 35);
+        tx.commit().unwrap();
+        assert_eq!(engine.count(), 1);
+
+        drop(engine);
+        fs::remove_dir_all("dbtruncate").unwrap();
+    }
+
+    #[test]
+    fn retention() {
+        use std::thread;
+
+        ensure_dir_nonexistent("dbretention");
+        let mut options = OpenOptions::new();
+        options.segment_rows(10).retain_rows(25);
+        let mut engine = options.open("dbretention").unwrap();
+        let append = |engine: &mut Engine, n: u64| {
+            let mut tx = engine.transaction().unwrap();
+            for _ in 0..n {
+                tx.append(b"0123456789").unwrap();
+            }
+            tx.commit().unwrap();
+        };
+        for _ in 0..4 {
+            append(&mut engine, 10);
+        }
+        assert_eq!(engine.first_row(), 15);
+        assert_eq!(engine.count(), 25);
+        assert_eq!(engine.segment_count(), 3);
+        drop(engine);
+
+        // Each segment of 10 rows takes 10 * 12 + 10 * 8 bytes.
+        options.retain_rows(u64::MAX).retain_bytes(450);
+        let mut engine = options.open("dbretention").unwrap();
+        append(&mut engine, 10);
+        assert_eq!(engine.first_row(), 30);
+        assert_eq!(engine.segment_count(), 2);
+        drop(engine);
+
+        options.retain_bytes(u64::MAX).retain_age(Duration::from_millis(500));
+        let mut engine = options.open("dbretention").unwrap();
+        thread::sleep(Duration::from_millis(600));
+        append(&mut engine, 3);
+        append(&mut engine, 4);
+        assert_eq!(engine.first_row(), 50);
+        assert_eq!(engine.count(), 7);
+        assert_eq!(engine.segment_count(), 1);
+        assert!(engine.get(49).is_err());
+        assert_eq!(engine.get(50).unwrap().unwrap().as_ref(), b"0123456789");
+
+        // Failing to enforce the limits doesn't fail the commit.
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let seen = errors.clone();
+        engine.add_error_hook(move |e| seen.lock().unwrap().push(e.kind()));
+        fs::create_dir("dbretention/MANIFEST.tmp").unwrap();
+        thread::sleep(Duration::from_millis(600));
+        append(&mut engine, 1);
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert_eq!(engine.first_row(), 50);
+        fs::remove_dir("dbretention/MANIFEST.tmp").unwrap();
+        append(&mut engine, 1);
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert_eq!(engine.first_row(), 57);
+
+        drop(engine);
+        fs::remove_dir_all("dbretention").unwrap();
+    }
+
+    #[test]
+    fn cold() {
+        ensure_dir_nonexistent("dbcold");
+        ensure_dir_nonexistent("dbcold_cold");
+        let mut options = OpenOptions::new();
+        options.segment_rows(10).cold_dir("dbcold_cold").cold_after(Duration::from_secs(0));
+        {
+            let mut engine = options.open("dbcold").unwrap();
+            let follower = OpenOptions::new().read_only(true).cold_dir("dbcold_cold").open("dbcold").unwrap();
+            for batch in 0..3u64 {
+                let mut tx = engine.transaction().unwrap();
+                for i in 0..10 {
+                    tx.append(&(batch * 10 + i).to_le_bytes()).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+            assert!(engine.segments().iter().all(|info| info.tier == Tier::Hot));
+            assert_eq!(engine.move_cold().unwrap(), 2);
+            let tiers = engine.segments().iter().map(|info| info.tier).collect::<Vec<_>>();
+            assert_eq!(tiers, vec![Tier::Cold, Tier::Cold, Tier::Hot]);
+            assert!(!Path::new("dbcold/LOG0").exists());
+            assert!(Path::new("dbcold_cold/LOG0").exists());
+            assert!(Path::new("dbcold/LOG2").exists());
+            assert_eq!(engine.get(5).unwrap().unwrap().as_ref(), &5u64.to_le_bytes());
+
+            // Cold segments still take deletions.
+            let mut tx = engine.transaction().unwrap();
+            tx.delete(3).unwrap();
+            tx.commit().unwrap();
+            assert!(Deleted::from_io(&engine.get(3).unwrap_err()).is_some());
+            assert_eq!(engine.move_cold().unwrap(), 1);
+
+            assert_eq!(follower.refresh().unwrap(), 30);
+            assert_eq!(follower.get(15).unwrap().unwrap().as_ref(), &15u64.to_le_bytes());
+        }
+        // The deleting transaction sealed segment 2.
+        assert_eq!(Manifest::load("dbcold").unwrap().cold, vec![0, 1, 2]);
+        assert!(Engine::open("dbcold").is_err());
+
+        // Pretend to crash while copying segment 3.
+        fs::write("dbcold_cold/LOG3.copying", b"partial").unwrap();
+        let engine = options.open("dbcold").unwrap();
+        assert!(!Path::new("dbcold_cold/LOG3.copying").exists());
+        assert_eq!(engine.count(), 30);
+        assert!(Deleted::from_io(&engine.get(3).unwrap_err()).is_some());
+        for i in (0..30u64).filter(|&i| i != 3) {
+            assert_eq!(engine.get(i).unwrap().unwrap().as_ref(), &i.to_le_bytes());
+        }
+        drop(engine);
+        fs::remove_dir_all("dbcold").unwrap();
+        fs::remove_dir_all("dbcold_cold").unwrap();
+
+        // A segment without commit times counts as committed when its
+        // log file was written.
+        options.cold_after(Duration::from_secs(3600));
+        let mut engine = options.open("dbcold").unwrap();
+        for i in 0..11u64 {
+            let mut tx = engine.transaction().unwrap();
+            tx.append(&i.to_le_bytes()).unwrap();
+            tx.commit().unwrap();
+        }
+        drop(engine);
+        fs::remove_file("dbcold/TIME0").unwrap();
+        let mut engine = options.open("dbcold").unwrap();
+        assert_eq!(engine.move_cold().unwrap(), 0);
+
+        drop(engine);
+        fs::remove_dir_all("dbcold").unwrap();
+        fs::remove_dir_all("dbcold_cold").unwrap();
+    }
+
+    #[test]
+    fn truncate_after() {
+        ensure_dir_nonexistent("dbtail");
+        let mut options = OpenOptions::new();
+        options.segment_rows(10).sparse(4);
+        let append = |engine: &mut Engine, from: u64, to: u64| {
+            for i in from..to {
+                let mut tx = engine.transaction().unwrap();
+                tx.append(&i.to_le_bytes()).unwrap();
+                tx.commit().unwrap();
+            }
+        };
+        {
+            let mut engine = options.open("dbtail").unwrap();
+            let follower = Engine::open_read_only("dbtail").unwrap();
+            append(&mut engine, 0, 35);
+            follower.refresh().unwrap();
+            assert!(engine.truncate_after(35).is_err());
+
+            // Deletions and updates of the kept rows stay, even if they
+            // committed after the cut.
+            let mut tx = engine.transaction().unwrap();
+            tx.delete(2).unwrap();
+            tx.update(3, b"new").unwrap();
+            tx.delete(20).unwrap();
+            tx.commit().unwrap();
+
+            engine.truncate_after(12).unwrap();
+            assert_eq!(engine.next_row(), 13);
+            assert_eq!(engine.segment_count(), 2);
+            assert!(!Path::new("dbtail/LOG2").exists());
+            assert!(engine.get(13).unwrap().is_none());
+            assert_eq!(engine.get(12).unwrap().unwrap().as_ref(), &12u64.to_le_bytes());
+            assert!(Deleted::from_io(&engine.get(2).unwrap_err()).is_some());
+            assert_eq!(engine.get(3).unwrap().unwrap().as_ref(), b"new");
+
+            // Waiting for rows ends on the truncation, with no new rows.
+            let start = Instant::now();
+            assert_eq!(follower.wait_for_rows(Duration::from_secs(10)).unwrap(), 0);
+            assert!(start.elapsed() < Duration::from_secs(10));
+            assert_eq!(follower.next_row(), 13);
+            assert!(follower.get(20).unwrap().is_none());
+
+            // Rows after the cut are written again.
+            append(&mut engine, 13, 15);
+            assert_eq!(engine.get(14).unwrap().unwrap().as_ref(), &14u64.to_le_bytes());
+
+            // The follower notices a truncation even if the files have
+            // grown back to their old lengths in the meantime.
+            follower.refresh().unwrap();
+            engine.truncate_after(12).unwrap();
+            let mut tx = engine.transaction().unwrap();
+            tx.append(&[13; 4]).unwrap();
+            tx.append(&[14; 12]).unwrap();
+            tx.commit().unwrap();
+            follower.refresh().unwrap();
+            assert_eq!(follower.next_row(), 15);
+            assert_eq!(follower.get(14).unwrap().unwrap().as_ref(), &[14; 12]);
+            append(&mut engine, 15, 17);
+        }
+
+        // Pretend to crash right after recording the truncation.
+        let mut manifest = Manifest::load("dbtail").unwrap();
+        assert_eq!(manifest.generation, 4);
+        manifest.truncating = Some(11);
+        manifest.store("dbtail").unwrap();
+        {
+            let mut engine = options.open("dbtail").unwrap();
+            assert_eq!(engine.next_row(), 11);
+            let manifest = Manifest::load("dbtail").unwrap();
+            assert_eq!(manifest.truncating, None);
+            assert_eq!(manifest.generation, 5);
+            append(&mut engine, 11, 12);
+            assert_eq!(engine.get(11).unwrap().unwrap().as_ref(), &11u64.to_le_bytes());
+        }
+        let engine = options.open("dbtail").unwrap();
+        assert_eq!(engine.next_row(), 12);
+        assert!(Deleted::from_io(&engine.get(2).unwrap_err()).is_some());
+        assert_eq!(engine.get(3).unwrap().unwrap().as_ref(), b"new");
+        for i in (0..12u64).filter(|&i| i != 2 && i != 3) {
+            assert_eq!(engine.get(i).unwrap().unwrap().as_ref(), &i.to_le_bytes());
+        }
+
+        drop(engine);
+        fs::remove_dir_all("dbtail").unwrap();
+    }
+
+    #[test]
+    fn reset() {
+        ensure_dir_nonexistent("dbreset");
+        let mut engine = OpenOptions::new().segment_rows(2).open("dbreset").unwrap();
+        let follower = Engine::open_read_only("dbreset").unwrap();
+        for i in 0..5u64 {
+            let mut tx = engine.transaction().unwrap();
+            tx.append(&i.to_le_bytes()).unwrap();
+            tx.commit().unwrap();
+        }
+        engine.truncate_before(2).unwrap();
+
+        engine.reset().unwrap();
+        assert_eq!(engine.next_row(), 0);
+        assert_eq!(engine.first_row(), 0);
+        assert_eq!(engine.segment_count(), 1);
+        assert!(engine.get(0).unwrap().is_none());
+        assert!(!Path::new("dbreset/LOG2").exists());
+
+        let mut tx = engine.transaction().unwrap();
+        assert_eq!(tx.append(b"again").unwrap(), 0);
+        tx.commit().unwrap();
+        follower.refresh().unwrap();
+        assert_eq!(follower.get(0).unwrap().unwrap().as_ref(), b"again");
+        drop(follower);
+        drop(engine);
+
+        let engine = Engine::open("dbreset").unwrap();
+        assert_eq!(engine.count(), 1);
+        assert_eq!(engine.get(0).unwrap().unwrap().as_ref(), b"again");
+
+        drop(engine);
+        fs::remove_dir_all("dbreset").unwrap();
+    }
+
+    #[test]
+    fn delete_and_compact() {
+        ensure_dir_nonexistent("dbdelete");
+        let mut options = OpenOptions::new();
+        options.segment_rows(10);
+        let payload = [7u8; 100];
+        {
+            let mut engine = options.open("dbdelete").unwrap();
+            let follower = Engine::open_read_only("dbdelete").unwrap();
+            for _ in 0..3 {
+                let mut tx = engine.transaction().unwrap();
+                for _ in 0..10 {
+                    tx.append(&payload).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+
+            let reader = engine.reader();
+            let mut tx = engine.transaction().unwrap();
+            assert!(tx.delete(30).is_err());
+            tx.delete(3).unwrap();
+            tx.delete(12).unwrap();
+            tx.delete(25).unwrap();
+            tx.append(b"after").unwrap();
+            // Not committed yet.
+            assert!(reader.get(3).unwrap().is_some());
+            tx.commit().unwrap();
+
+            let err = engine.get(3).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+            assert_eq!(Deleted::from_io(&err), Some(Deleted { row: 3 }));
+            assert!(engine.entry_len(12).is_err());
+            assert_eq!(engine.get(4).unwrap().unwrap().as_ref(), &payload[..]);
+
+            follower.refresh().unwrap();
+            assert!(Deleted::from_io(&follower.get(25).unwrap_err()).is_some());
+
+            // A rolled back deletion has no effect.
+            let mut tx = engine.transaction().unwrap();
+            tx.delete(5).unwrap();
+            drop(tx);
+            assert!(engine.get(5).unwrap().is_some());
+
+            let rows: Vec<_> = engine.subscribe(0).take(28).map(|r| r.unwrap().0).collect();
+            assert_eq!(rows.len(), 28);
+            assert!(!rows.contains(&3) && !rows.contains(&12) && !rows.contains(&25));
+        }
+
+        // Pretend to crash after writing the tombstone of a transaction.
+        let tombstones = crate::tombstone::Tombstones::open("dbdelete/DEL0", false).unwrap();
+        tombstones.write(&[6], 100).unwrap();
+        drop(tombstones);
+
+        let mut engine = options.open("dbdelete").unwrap();
+        assert!(engine.get(6).unwrap().is_some());
+        assert!(Deleted::from_io(&engine.get(12).unwrap_err()).is_some());
+
+        let size = fs::metadata("dbdelete/LOG0").unwrap().len();
+        // The last transaction started segment 3, so all the deleted
+        // rows are in sealed segments.
+        assert_eq!(engine.compact().unwrap(), 3 * 100);
+        assert_eq!(fs::metadata("dbdelete/LOG0").unwrap().len(), size - 100);
+        assert_eq!(engine.compact().unwrap(), 0);
+        assert!(!Path::new("dbdelete/LOG0.compact").exists());
+        assert!(Deleted::from_io(&engine.get(3).unwrap_err()).is_some());
+        for row in (0..30).filter(|row| ![3, 12, 25].contains(row)) {
+            assert_eq!(engine.get(row).unwrap().unwrap().as_ref(), &payload[..]);
+        }
+        drop(engine);
+
+        // Pretend to crash in the middle of moving compacted files.
+        fs::copy("dbdelete/LOG1", "dbdelete/LOG1.compact").unwrap();
+        let mut manifest = Manifest::load("dbdelete").unwrap();
+        manifest.compacting = Some(1);
+        manifest.store("dbdelete").unwrap();
+        fs::copy("dbdelete/LOG2", "dbdelete/LOG2.compact").unwrap();
+        let engine = options.open("dbdelete").unwrap();
+        assert_eq!(Manifest::load("dbdelete").unwrap().compacting, None);
+        assert!(!Path::new("dbdelete/LOG1.compact").exists());
+        assert!(!Path::new("dbdelete/LOG2.compact").exists());
+        assert_eq!(engine.get(30).unwrap().unwrap().as_ref(), b"after");
+        assert_eq!(engine.get(13).unwrap().unwrap().as_ref(), &payload[..]);
+
+        drop(engine);
+        fs::remove_dir_all("dbdelete").unwrap();
+    }
+
+    #[test]
+    fn update() {
+        ensure_dir_nonexistent("dbupdate");
+        let mut options = OpenOptions::new();
+        options.segment_rows(4);
+        {
+            let mut engine = options.open("dbupdate").unwrap();
+            let follower = Engine::open_read_only("dbupdate").unwrap();
+            for rows in &[0..4u8, 4..6] {
+                let mut tx = engine.transaction().unwrap();
+                for i in rows.clone() {
+                    tx.append(&[i]).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+
+            let reader = engine.reader();
+            let mut tx = engine.transaction().unwrap();
+            assert!(tx.update(6, b"new").is_err());
+            tx.update(1, b"one").unwrap();
+            tx.update(1, b"uno").unwrap();
+            tx.update(5, b"five").unwrap();
+            // Not committed yet.
+            assert_eq!(reader.get(1).unwrap().unwrap().as_ref(), &[1]);
+            tx.commit().unwrap();
+
+            assert_eq!(engine.get(1).unwrap().unwrap().as_ref(), b"uno");
+            assert_eq!(engine.entry_len(5).unwrap(), Some(4));
+            assert_eq!(engine.read_range(5, 1, 2).unwrap().unwrap().as_ref(), b"iv");
+            assert_eq!(engine.get_version(1, 0).unwrap().unwrap().as_ref(), &[1]);
+            assert_eq!(engine.get_version(1, 1).unwrap().unwrap().as_ref(), b"one");
+            assert_eq!(engine.get_version(1, 3).unwrap(), None);
+            assert_eq!(engine.get_version(2, 0).unwrap().unwrap().as_ref(), &[2]);
+
+            follower.refresh().unwrap();
+            assert_eq!(follower.get(5).unwrap().unwrap().as_ref(), b"five");
+
+            // A rolled back update has no effect.
+            let mut tx = engine.transaction().unwrap();
+            tx.update(2, b"two").unwrap();
+            drop(tx);
+            assert_eq!(engine.get(2).unwrap().unwrap().as_ref(), &[2]);
+
+            let mut tx = engine.transaction().unwrap();
+            tx.delete(3).unwrap();
+            tx.commit().unwrap();
+            let mut tx = engine.transaction().unwrap();
+            assert!(Deleted::from_io(&tx.update(3, b"three").unwrap_err()).is_some());
+        }
+
+        // Pretend to crash after writing the new version of a
+        // transaction.
+        let versions = crate::versions::Versions::open("dbupdate/VER0", "dbupdate/OVR0", false).unwrap();
+        versions.write(&[(0, b"zero")], 100).unwrap();
+        drop(versions);
+
+        let mut engine = options.open("dbupdate").unwrap();
+        assert_eq!(engine.get(0).unwrap().unwrap().as_ref(), &[0]);
+        assert_eq!(engine.get(1).unwrap().unwrap().as_ref(), b"uno");
+
+        // Compaction keeps the latest versions only.
+        engine.compact().unwrap();
+        assert_eq!(fs::metadata("dbupdate/VER0").unwrap().len(), 0);
+        assert_eq!(engine.get(1).unwrap().unwrap().as_ref(), b"uno");
+        assert_eq!(engine.get_version(1, 0).unwrap().unwrap().as_ref(), b"uno");
+        assert_eq!(engine.get_version(1, 1).unwrap(), None);
+        // The active segment isn't compacted yet.
+        assert_eq!(engine.get_version(5, 0).unwrap().unwrap().as_ref(), &[5]);
+        assert_eq!(engine.get(5).unwrap().unwrap().as_ref(), b"five");
+        drop(engine);
+
+        let engine = options.open("dbupdate").unwrap();
+        assert_eq!(engine.get(1).unwrap().unwrap().as_ref(), b"uno");
+        assert_eq!(engine.get(5).unwrap().unwrap().as_ref(), b"five");
+
+        drop(engine);
+        fs::remove_dir_all("dbupdate").unwrap();
+    }
+
+    /// Whether holes can be punched into files here, probed with a
+    /// scratch file.
+    fn holes_supported() -> bool {
+        ensure_nonexistent("HOLE_probe");
+        fs::write("HOLE_probe", [1; 8192]).unwrap();
+        let file = fs::OpenOptions::new().write(true).open("HOLE_probe").unwrap();
+        file.sync_all().unwrap();
+        let probe = DeadRanges::new();
+        probe.add(0, 4096);
+        let supported = probe.punch(&file).unwrap() > 0;
+        fs::remove_file("HOLE_probe").unwrap();
+        supported
+    }
+
+    #[test]
+    fn punch_holes() {
+        ensure_dir_nonexistent("dbholes");
+        let mut options = OpenOptions::new();
+        options.sparse(4);
+        let entry = |i: u64| vec![i as u8; 8000];
+        let dead = 2 * 8000 + 8 * (8000 + HEADER_SIZE);
+        {
+            let mut engine = options.open("dbholes").unwrap();
+            let mut tx = engine.transaction().unwrap();
+            for i in 0..30 {
+                tx.append(&entry(i)).unwrap();
+            }
+            tx.commit().unwrap();
+            assert_eq!(engine.dead_bytes(), 0);
+
+            let mut tx = engine.transaction().unwrap();
+            tx.delete(20).unwrap();
+            tx.delete(21).unwrap();
+            tx.commit().unwrap();
+            assert_eq!(engine.dead_bytes(), 2 * 8000);
+
+            // The dead prefix ends where the index starts looking for
+            // row 10, which is row 8.
+            engine.truncate_before(10).unwrap();
+            assert_eq!(engine.dead_bytes(), dead);
+
+            // Nothing is released where holes are not supported.
+            let released = engine.punch_holes().unwrap();
+            assert!(released <= dead);
+            assert_eq!(released > 0, holes_supported());
+            assert_eq!(engine.dead_bytes(), 0);
+            for i in (10..30).filter(|i| ![20, 21].contains(i)) {
+                assert_eq!(engine.get(i).unwrap().unwrap().as_ref(), &entry(i)[..]);
+            }
+            assert_eq!(engine.count(), 20);
+
+            let mut tx = engine.transaction().unwrap();
+            tx.append(&entry(30)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        // Dead ranges are found again on open.
+        let mut engine = options.open("dbholes").unwrap();
+        assert_eq!(engine.dead_bytes(), dead);
+        assert_eq!(engine.next_row(), 31);
+        assert_eq!(engine.get(30).unwrap().unwrap().as_ref(), &entry(30)[..]);
+        assert_eq!(engine.punch_holes().unwrap(), 0);
+
+        // Truncating everything keeps the last row, which recovery
+        // reads.
+        engine.truncate_before(31).unwrap();
+        engine.punch_holes().unwrap();
+        drop(engine);
+        let mut engine = options.open("dbholes").unwrap();
+        assert_eq!(engine.next_row(), 31);
+        let mut tx = engine.transaction().unwrap();
+        tx.append(&entry(31)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(engine.get(31).unwrap().unwrap().as_ref(), &entry(31)[..]);
+
+        drop(engine);
+        fs::remove_dir_all("dbholes").unwrap();
+    }
+
+    #[test]
+    fn redact() {
+        use std::io::Write;
+
+        ensure_dir_nonexistent("dbredact");
+        {
+            let mut engine = OpenOptions::new().sparse(4).open("dbredact").unwrap();
+            let mut tx = engine.transaction().unwrap();
+            for i in 0..10u64 {
+                tx.append(format!("secret {}", i).as_bytes()).unwrap();
+            }
+            tx.commit().unwrap();
+            assert!(engine.redact(10).is_err());
+
+            engine.redact(5).unwrap();
+            assert!(Deleted::from_io(&engine.get(5).unwrap_err()).is_some());
+            assert_eq!(engine.get(6).unwrap().unwrap().as_ref(), b"secret 6");
+            let log = fs::read("dbredact/LOG0").unwrap();
+            assert!(!log.windows(8).any(|w| w == b"secret 5"));
+            assert_eq!(log.len(), 10 * (2 + 8));
+
+            let redactions = engine.redactions().unwrap();
+            assert_eq!(redactions.len(), 1);
+            assert_eq!((redactions[0].row, redactions[0].done), (5, true));
+        }
+
+        // Pretend to crash right after recording a redaction.
+        fs::OpenOptions::new().append(true).open("dbredact/AUDIT").unwrap()
+            .write_all(b"redact 2 0\n").unwrap();
+        let mut engine = Engine::open("dbredact").unwrap();
+        assert!(!fs::read("dbredact/LOG0").unwrap().windows(8).any(|w| w == b"secret 2"));
+        assert!(Deleted::from_io(&engine.get(2).unwrap_err()).is_some());
+        assert!(engine.redactions().unwrap().iter().all(|r| r.done));
+        assert_eq!(engine.get(9).unwrap().unwrap().as_ref(), b"secret 9");
+
+        // Redactions outlive a tail truncation which keeps their rows.
+        engine.truncate_after(6).unwrap();
+        for &row in &[2, 5] {
+            assert!(Deleted::from_io(&engine.get(row).unwrap_err()).is_some());
+        }
+        drop(engine);
+        let engine = Engine::open("dbredact").unwrap();
+        for &row in &[2, 5] {
+            assert!(Deleted::from_io(&engine.get(row).unwrap_err()).is_some());
+        }
+        assert_eq!(engine.redactions().unwrap().len(), 2);
+
+        drop(engine);
+        fs::remove_dir_all("dbredact").unwrap();
+    }
+
+    #[test]
+    fn operations() {
+        use byteorder::{ByteOrder, LittleEndian};
+
+        /// Counts rows, and how many it applied since it was created.
+        #[derive(Default)]
+        struct Count {
+            rows: u64,
+            applied: u64,
+        }
+
+        impl Operation for Count {
+            fn apply(&mut self, _row: RowId, _entry: &[u8]) -> Result<()> {
+                self.rows += 1;
+                self.applied += 1;
+                Ok(())
+            }
+
+            fn save(&self) -> Vec<u8> {
+                self.rows.to_le_bytes().to_vec()
+            }
+
+            fn load(&mut self, state: &[u8]) -> Result<()> {
+                self.rows = if state.is_empty() { 0 } else { LittleEndian::read_u64(state) };
+                Ok(())
+            }
+        }
+
+        ensure_dir_nonexistent("dbops_engine");
+        let mut options = OpenOptions::new();
+        options.checkpoint_rows(4);
+        let append = |engine: &mut Engine, n: u64| {
+            for _ in 0..n {
+                let mut tx = engine.transaction().unwrap();
+                tx.append(b"row").unwrap();
+                tx.commit().unwrap();
+            }
+        };
+        let count = |engine: &Engine| engine.with_operation("count", |op: &Count| (op.rows, op.applied)).unwrap();
+        {
+            let mut engine = options.open("dbops_engine").unwrap();
+            append(&mut engine, 3);
+            engine.register("count", Count::default()).unwrap();
+            assert!(engine.register("count", Count::default()).is_err());
+            assert_eq!(count(&engine), (3, 3));
+            append(&mut engine, 7);
+            assert_eq!(count(&engine), (10, 10));
+            assert_eq!(&fs::read("dbops_engine/OP_count").unwrap()[..8], &8u64.to_le_bytes());
+        }
+
+        // Only the rows after the checkpoint are replayed.
+        let mut engine = options.open("dbops_engine").unwrap();
+        append(&mut engine, 2);
+        engine.register("count", Count::default()).unwrap();
+        assert_eq!(count(&engine), (12, 2));
+
+        let mut follower = Engine::open_read_only("dbops_engine").unwrap();
+        follower.register("count", Count::default()).unwrap();
+        assert_eq!(count(&follower), (12, 2));
+
+        // Removed rows are unapplied by replaying from a checkpoint
+        // before them, or from the start.
+        engine.truncate_after(5).unwrap();
+        assert!(!Path::new("dbops_engine/OP_count").exists());
+        assert_eq!(count(&engine), (6, 8));
+        append(&mut engine, 1);
+        assert_eq!(count(&engine), (7, 9));
+        follower.refresh().unwrap();
+        assert_eq!(count(&follower).0, 7);
+
+        engine.reset().unwrap();
+        assert_eq!(count(&engine).0, 0);
+
+        drop(follower);
+        drop(engine);
+        fs::remove_dir_all("dbops_engine").unwrap();
+    }
+
+    #[test]
+    fn operation_changes() {
+        use std::sync::atomic::AtomicBool;
+        use byteorder::{ByteOrder, LittleEndian};
+
+        /// Sums the lengths of the entries, and fails on empty ones
+        /// while `strict`.
+        #[derive(Default)]
+        struct Total {
+            bytes: u64,
+            strict: Arc<AtomicBool>,
+        }
+
+        impl Operation for Total {
+            fn apply(&mut self, _row: RowId, entry: &[u8]) -> Result<()> {
+                if entry.is_empty() && self.strict.load(Ordering::SeqCst) {
+                    return Err(Error::new(ErrorKind::InvalidData, "empty entry"))
+                }
+                self.bytes += entry.len() as u64;
+                Ok(())
+            }
+
+            fn save(&self) -> Vec<u8> {
+                self.bytes.to_le_bytes().to_vec()
+            }
+
+            fn load(&mut self, state: &[u8]) -> Result<()> {
+                self.bytes = if state.is_empty() { 0 } else { LittleEndian::read_u64(state) };
+                Ok(())
+            }
+        }
+
+        ensure_dir_nonexistent("dbops_changes");
+        let append = |engine: &mut Engine, entries: &[&[u8]]| {
+            let mut tx = engine.transaction().unwrap();
+            for entry in entries {
+                tx.append(entry).unwrap();
+            }
+            tx.commit().unwrap();
+        };
+        let total = |engine: &Engine| engine.with_operation("total", |op: &Total| op.bytes).unwrap();
+        let mut engine = Engine::open("dbops_changes").unwrap();
+        engine.register("total", Total::default()).unwrap();
+        append(&mut engine, &[b"a", b"bb", b"ccc"]);
+        assert_eq!(total(&engine), 6);
+
+        // Applied rows stay as they were applied, also when replayed.
+        let mut tx = engine.transaction().unwrap();
+        tx.update(1, b"bbbbbbbbbb").unwrap();
+        tx.delete(2).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(total(&engine), 6);
+        let mut replayed = Engine::open_read_only("dbops_changes").unwrap();
+        replayed.register("total", Total::default()).unwrap();
+        assert_eq!(total(&replayed), 6);
+        drop(replayed);
+
+        // A failing operation doesn't fail the commit, and is tried
+        // again after the next one.
+        let strict = Arc::new(AtomicBool::new(true));
+        drop(engine);
+        let mut engine = Engine::open("dbops_changes").unwrap();
+        engine.register("total", Total { bytes: 0, strict: strict.clone() }).unwrap();
+        let errors = Arc::new(Mutex::new(0));
+        let seen = errors.clone();
+        engine.add_error_hook(move |_| *seen.lock().unwrap() += 1);
+        append(&mut engine, &[b"", b"dddd"]);
+        assert_eq!(*errors.lock().unwrap(), 1);
+        assert_eq!(total(&engine), 6);
+        strict.store(false, Ordering::SeqCst);
+        append(&mut engine, &[b"e"]);
+        assert_eq!(*errors.lock().unwrap(), 1);
+        assert_eq!(total(&engine), 11);
+
+        // A follower notices removed rows even if as many are written
+        // again.
+        let mut follower = Engine::open_read_only("dbops_changes").unwrap();
+        follower.register("total", Total::default()).unwrap();
+        assert_eq!(total(&follower), 11);
+        engine.truncate_after(2).unwrap();
+        append(&mut engine, &[b"ffffff", b"g", b"h"]);
+        assert_eq!(total(&engine), 14);
+        follower.refresh().unwrap();
+        assert_eq!(total(&follower), 14);
+
+        drop(follower);
+        drop(engine);
+        fs::remove_dir_all("dbops_changes").unwrap();
+    }
+
+    #[test]
+    fn hooks() {
+        ensure_dir_nonexistent("dbhooks");
+        let mut engine = Engine::open("dbhooks").unwrap();
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        {
+            let committed = committed.clone();
+            engine.add_post_commit_hook(move |rows| committed.lock().unwrap().push(rows));
+        }
+        engine.add_pre_commit_hook(|pending| {
+            let updated = pending.updates.iter().map(|&(_, entry)| entry);
+            if pending.entries.iter().map(|entry| &entry[..]).chain(updated).any(|entry| entry.is_empty()) {
+                return Err(Error::new(ErrorKind::InvalidInput, "empty entry"))
+            }
+            if pending.deletes.contains(&1) {
+                return Err(Error::new(ErrorKind::PermissionDenied, "row 1 is kept"))
+            }
+            if pending.info_updates.contains_key(&b"frozen"[..]) {
+                return Err(Error::new(ErrorKind::PermissionDenied, "frozen"))
+            }
+            Ok(())
+        });
+
+        let mut tx = engine.transaction().unwrap();
+        tx.append(b"one").unwrap();
+        tx.append(b"two").unwrap();
+        tx.commit().unwrap();
+
+        // Vetoed transactions are rolled back.
+        let mut tx = engine.transaction().unwrap();
+        tx.append(b"three").unwrap();
+        tx.append(b"").unwrap();
+        assert_eq!(tx.commit().unwrap_err().kind(), ErrorKind::InvalidInput);
+        let mut tx = engine.transaction().unwrap();
+        tx.put_info(b"frozen", b"yes");
+        assert_eq!(tx.commit().unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        // So are updates and deletions.
+        let mut tx = engine.transaction().unwrap();
+        tx.update(0, b"").unwrap();
+        assert_eq!(tx.commit().unwrap_err().kind(), ErrorKind::InvalidInput);
+        let mut tx = engine.transaction().unwrap();
+        tx.delete(1).unwrap();
+        assert_eq!(tx.commit().unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(engine.get(0).unwrap().unwrap().as_ref(), b"one");
+        assert_eq!(engine.get(1).unwrap().unwrap().as_ref(), b"two");
+        assert!(engine.info().is_empty());
+        assert_eq!(engine.next_row(), 2);
+
+        let mut tx = engine.transaction().unwrap();
+        assert_eq!(tx.append(b"three").unwrap(), 2);
+        tx.commit().unwrap();
+        let mut tx = engine.transaction().unwrap();
+        tx.delete(0).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(*committed.lock().unwrap(), vec![0..2, 2..3, 3..3]);
+        assert_eq!(engine.get(2).unwrap().unwrap().as_ref(), b"three");
+
+        drop(engine);
+        fs::remove_dir_all("dbhooks").unwrap();
+    }
+
     #[test]
     fn bulk_append() {
         ensure_dir_nonexistent("db1");
@@ -212,7 +3007,7 @@ mod tests {
         let engine = Engine::open("db1").unwrap();
         assert_eq!(engine.count(), rows.len());
         for i in 0..engine.count() {
-            let data = engine.get(i as u32).unwrap().unwrap();
+            let data = engine.get(i as RowId).unwrap().unwrap();
             assert_eq!(data.as_ref(), text);
         }
 
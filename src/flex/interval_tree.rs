@@ -12,7 +12,6 @@
 use std::cmp::Ordering::*;
 use slab::Slab;
 use std::mem;
-use std::ptr;
 
 /// This trait defines a merge operator, which is used to merge a pair
 /// of values of the same type into a single value.
@@ -41,30 +40,51 @@ pub type Index = usize;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum FindResult {
+    /// ```text
     ///     ====
     /// --      --
+    /// ```
     Miss,
 
     /// Equal
     Equal(Index),
 
+    /// ```text
     ///   ===
     /// ------
+    /// ```
     Outside(Index),
 
+    /// ```text
     /// ====
     ///  --
+    /// ```
     Inside(Index),
 
+    /// ```text
     ///  ====
     /// --
+    /// ```
     Left(Index),
 
+    /// ```text
     /// ====
     ///   ---
+    /// ```
     Right(Index),
 }
 
+impl FindResult {
+    /// The interval found, if any.
+    pub fn index(self) -> Option<Index> {
+        match self {
+            FindResult::Miss => None,
+            FindResult::Equal(n) | FindResult::Outside(n) | FindResult::Inside(n)
+                | FindResult::Left(n) | FindResult::Right(n) => Some(n),
+        }
+    }
+}
+
 pub enum FindKey {
     Right(Index),
     Left(Index),
@@ -114,29 +134,26 @@ where
         }
     }
 
-    /// Insert a new interval; if there's an overlapping interval,
-    /// merge with it.
-    pub fn insert(&mut self, a: K, b: K, value: V) {
-        match self.find(a, b) {
-            FindResult::Miss => {
-                self.insert_nonoverlapping(a, b, value)
-            }
-            FindResult::Left(n) => {
-                unimplemented!()
-            }
-            FindResult::Right(n) => {
-                unimplemented!()
-            }
-            FindResult::Outside(n) => {
-                unimplemented!()
-            }
-            FindResult::Inside(n) => {
-                unimplemented!()
-            }
-            FindResult::Equal(n) => {
-                self.node_mut(n).value = value;
+    /// Insert a new interval.  Intervals overlapping or adjacent to
+    /// it are merged into it, and so are their values: the value of an
+    /// interval starting before `a` is merged from the left, and that
+    /// of an interval ending after `b` from the right.  Intervals
+    /// within [a,b) are replaced.
+    ///
+    /// Values are merged as they are, so the overlapping parts, if
+    /// any, are not deduplicated.
+    pub fn insert(&mut self, mut a: K, mut b: K, mut value: V) {
+        while let Some(n) = self.find_touching(a, b) {
+            let (na, nb, nvalue) = self.remove(n);
+            if na < a {
+                value.merge_left(nvalue);
+                a = na;
+            } else if nb > b {
+                value.merge_right(nvalue);
             }
+            b = b.max(nb);
         }
+        self.insert_nonoverlapping(a, b, value)
     }
 
     /// Insert a new interval into this tree.
@@ -177,119 +194,138 @@ where
         self.repair_after_insert(id);
     }
 
-    /// Remove node del from the tree.
-    pub fn remove(&mut self, del: Index) {
-        let repl = self.smallest_right(del);
-
-        if repl.is_none() && self.node(del).l.is_none() {
-            self.free(del);
-            self.root = None;
-            return
-        } else if repl.is_none() && self.node(del).l.is_some() {
-            self.free(del);
-            self.root = self.node(del).l;
-            self.root_mut().unwrap().1.p = None;
-            return
+    /// Remove node z from the tree, and return its interval and
+    /// value.
+    pub fn remove(&mut self, z: Index) -> (K, K, V) {
+        // y is the node moved out of its place, and x is the node
+        // taking its place, whose parent is xp.
+        let mut y_color = self.node(z).color;
+        let (x, xp);
+        match (self.node(z).l, self.node(z).r) {
+            (None, r) => {
+                x = r;
+                xp = self.node(z).p;
+                self.transplant(z, r);
+            }
+            (l, None) => {
+                x = l;
+                xp = self.node(z).p;
+                self.transplant(z, l);
+            }
+            (Some(l), Some(r)) => {
+                let y = self.smallest_right(z).unwrap();
+                y_color = self.node(y).color;
+                x = self.node(y).r;
+                if y == r {
+                    xp = Some(y);
+                } else {
+                    xp = self.node(y).p;
+                    self.transplant(y, x);
+                    self.node_mut(y).r = Some(r);
+                    self.node_mut(r).p = Some(y);
+                }
+                self.transplant(z, Some(y));
+                self.node_mut(y).l = Some(l);
+                self.node_mut(l).p = Some(y);
+                self.node_mut(y).color = self.node(z).color;
+            }
         }
 
-        // Swap del and r, moving down the del to a better place.
-        let repl = repl.unwrap();
-        self.swap(del, repl);
-
-        // Then delete repl (was del).
-        self.unlink_one(repl);
-        self.free(repl);
-
-        // Maintain
-        
-    }
-
-    /// Swaps the contents of node p and q, keeping the original index
-    /// and pointer.
-    fn swap(&mut self, p: Index, q: Index) {
-        unsafe {
-            ptr::swap(&mut self.tree[p], &mut self.tree[q]);
-            ptr::swap(&mut self.tree[p].p, &mut self.tree[q].p);
+        let mut cur = xp;
+        while let Some(n) = cur {
+            self.update(n);
+            cur = self.node(n).p;
+        }
+        if y_color == Black {
+            self.repair_after_remove(x, xp);
         }
-    }
 
-    /// Unlink a node with only right child or no children.
-    ///
-    /// `left` indicates whether n is the left child (-1), or right child (1).
-    fn unlink_one(&mut self, n: Index) {
-        let r = self.node(n).r; // right child of n, taking place of n
+        let node = self.free(z);
+        (node.a, node.b, node.value)
+    }
 
-        // n is Red, so no children. n is safely deleted.
-        if self.node(n).color == Red {
-            return
+    /// Put the subtree v in the place of the subtree u.
+    fn transplant(&mut self, u: Index, v: Option<Index>) {
+        let up = self.node(u).p;
+        match up {
+            None => self.root = v,
+            Some(p) if self.node(p).l == Some(u) => self.node_mut(p).l = v,
+            Some(p) => self.node_mut(p).r = v,
         }
-
-        // r takes the place of n.
-        if let Some(r) = r {
-            self.replace(n, r);
+        if let Some(v) = v {
+            self.node_mut(v).p = up;
         }
-
-        // n is Black
-        self.bubble_black(r.unwrap());
     }
 
-    /// Node n is colored double black. Bubble the extraneous black up
-    /// until it's consumed.
-    fn bubble_black(&mut self, mut n: Index) {
-        loop {
-            if self.node(n).p.is_none() {
-                break
-            } else if self.node(n).color == Red {
-                self.node_mut(n).color = Black;
-                break
-            }
-
-            // n is Black and is not root
-            let (s, p) = self.sibling_and_parent(n).unwrap();
-            let p_color = self.node(p).color;
-            let sl = self.node(s).l;
-            let sr = self.node(s).r;
-
-            if self.node(s).color == Red {
-                self.rotate_up(s);
-                self.node_mut(s).color = Black;
-                self.node_mut(p).color = Red;
-                continue
-            } else if sl.is_some() && self.node(sl.unwrap()).color == Red {
-                self.rotate_up(sl.unwrap());
-                self.rotate_up(sl.unwrap());
-                self.node_mut(sl.unwrap()).color = p_color;
-                break
-            } else if sr.is_some() && self.node(sr.unwrap()).color == Red {
-                self.rotate_up(s);
-                self.node_mut(s).color = p_color;
-                self.node_mut(sr.unwrap()).color = Black;
-                break
+    /// Node x, whose parent is xp, has an extra black after a
+    /// removal.  Move it up until it's consumed.
+    fn repair_after_remove(&mut self, mut x: Option<Index>, mut xp: Option<Index>) {
+        while x != self.root && !self.is_red(x) {
+            let p = xp.unwrap();
+            if x == self.node(p).l {
+                let mut w = self.node(p).r.unwrap();
+                if self.is_red(Some(w)) {
+                    self.node_mut(w).color = Black;
+                    self.node_mut(p).color = Red;
+                    self.rotate_left(p);
+                    w = self.node(p).r.unwrap();
+                }
+                if !self.is_red(self.node(w).l) && !self.is_red(self.node(w).r) {
+                    self.node_mut(w).color = Red;
+                    x = Some(p);
+                    xp = self.node(p).p;
+                } else {
+                    if !self.is_red(self.node(w).r) {
+                        let wl = self.node(w).l.unwrap();
+                        self.node_mut(wl).color = Black;
+                        self.node_mut(w).color = Red;
+                        self.rotate_right(w);
+                        w = self.node(p).r.unwrap();
+                    }
+                    self.node_mut(w).color = self.node(p).color;
+                    self.node_mut(p).color = Black;
+                    let wr = self.node(w).r.unwrap();
+                    self.node_mut(wr).color = Black;
+                    self.rotate_left(p);
+                    x = self.root;
+                }
             } else {
-                self.node_mut(s).color = Red;
-                self.node_mut(p).color = Black;
-                if self.node(p).color == Black {
-                    n = p;
-                    continue;
+                let mut w = self.node(p).l.unwrap();
+                if self.is_red(Some(w)) {
+                    self.node_mut(w).color = Black;
+                    self.node_mut(p).color = Red;
+                    self.rotate_right(p);
+                    w = self.node(p).l.unwrap();
+                }
+                if !self.is_red(self.node(w).l) && !self.is_red(self.node(w).r) {
+                    self.node_mut(w).color = Red;
+                    x = Some(p);
+                    xp = self.node(p).p;
                 } else {
-                    break;
+                    if !self.is_red(self.node(w).l) {
+                        let wr = self.node(w).r.unwrap();
+                        self.node_mut(wr).color = Black;
+                        self.node_mut(w).color = Red;
+                        self.rotate_left(w);
+                        w = self.node(p).l.unwrap();
+                    }
+                    self.node_mut(w).color = self.node(p).color;
+                    self.node_mut(p).color = Black;
+                    let wl = self.node(w).l.unwrap();
+                    self.node_mut(wl).color = Black;
+                    self.rotate_right(p);
+                    x = self.root;
                 }
             }
         }
+        if let Some(x) = x {
+            self.node_mut(x).color = Black;
+        }
     }
 
-    /// Replace node u with v.
-    ///
-    /// Panics if u is root.
-    fn replace(&mut self, u: Index, v: Index) {
-        let up = self.node(u).p.unwrap();
-        self.node_mut(v).p = Some(up);
-        if self.node(up).l == Some(u) {
-            self.node_mut(up).l = Some(v);
-        } else {
-            self.node_mut(up).r = Some(v);
-        }
-        self.update(up);
+    /// Missing nodes count as black.
+    fn is_red(&self, n: Option<Index>) -> bool {
+        n.map(|n| self.node(n).color == Red).unwrap_or(false)
     }
 
     /// Take node out of slab. This should be the last step of the
@@ -348,19 +384,19 @@ where
                 debug_assert!(b > node.a && a < node.b);
                 match (a.cmp(&node.a), b.cmp(&node.b)) {
                     (Equal, Equal) => {
-                        return FindResult::Equal(dest)
+                        FindResult::Equal(dest)
                     }
                     (Less, Greater) | (Less, Equal) | (Equal, Greater) => {
-                        return FindResult::Outside(dest)
+                        FindResult::Outside(dest)
                     }
                     (Greater, Less) | (Greater, Equal) | (Equal, Less) => {
-                        return FindResult::Inside(dest)
+                        FindResult::Inside(dest)
                     }
                     (Less, Less) => {
-                        return FindResult::Left(dest)
+                        FindResult::Left(dest)
                     }
                     (Greater, Greater) => {
-                        return FindResult::Right(dest)
+                        FindResult::Right(dest)
                     }
                 }
             }
@@ -374,6 +410,23 @@ where
             if b > node.a && a < node.b {
                 return cur
             }
+            if node.l.is_some() && a < self.node(node.l.unwrap()).m {
+                cur = node.l;
+            } else {
+                cur = node.r;
+            }
+        }
+        None
+    }
+
+    /// Find an interval overlapping with or adjacent to [a, b).
+    fn find_touching(&self, a: K, b: K) -> Option<Index> {
+        let mut cur = self.root;
+        while let Some(node) = cur {
+            let node = self.node(node);
+            if b >= node.a && a <= node.b {
+                return cur
+            }
             if node.l.is_some() && a <= self.node(node.l.unwrap()).m {
                 cur = node.l;
             } else {
@@ -383,6 +436,33 @@ where
         None
     }
 
+    /// Whether there is no interval.
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Get the interval and the value of node n.
+    pub fn get(&self, n: Index) -> (K, K, &V) {
+        let node = self.node(n);
+        (node.a, node.b, &node.value)
+    }
+
+    /// Get the interval and the value of node n, and let the value be
+    /// modified.
+    pub fn get_mut(&mut self, n: Index) -> (K, K, &mut V) {
+        let node = self.node_mut(n);
+        (node.a, node.b, &mut node.value)
+    }
+
+    /// Iterate over the intervals and their values, in order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let mut next = self.root;
+        while let Some(l) = next.and_then(|n| self.node(n).l) {
+            next = Some(l);
+        }
+        Iter { tree: self, next }
+    }
+
     fn root(&self) -> Option<(Index, &Node<K,V>)> {
         self.root.map(|x| (x, unsafe { self.node(x) }))
     }
@@ -442,16 +522,6 @@ where
         &mut self.tree[id]
     }
 
-    /// Move the node up by rotating. Panics if is root.
-    fn rotate_up(&mut self, o: Index) {
-        let p = self.node(o).p.unwrap();
-        if Some(o) == self.node(p).l {
-            self.rotate_right(p);
-        } else {
-            self.rotate_left(p);
-        }
-    }
-
     /// Panics if o.r is None.
     fn rotate_left(&mut self, o: Index) {
         let s = self.node(o).r.unwrap();
@@ -543,6 +613,50 @@ where
     }
 }
 
+/// An iterator over the intervals of an `IntervalTree`, in order.
+pub struct Iter<'a, K, V>
+where
+    K: Eq + Ord + Copy,
+    V: Clone + Merge,
+{
+    tree: &'a IntervalTree<K, V>,
+    next: Option<Index>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V>
+where
+    K: Eq + Ord + Copy,
+    V: Clone + Merge,
+{
+    type Item = (K, K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.next?;
+        let node = self.tree.node(n);
+        self.next = match self.tree.smallest_right(n) {
+            Some(r) => Some(r),
+            None => {
+                // Go up until coming from a left child.
+                let mut cur = n;
+                loop {
+                    match self.tree.node(cur).p {
+                        Some(p) if self.tree.node(p).l == Some(cur) => break Some(p),
+                        Some(p) => cur = p,
+                        None => break None,
+                    }
+                }
+            }
+        };
+        Some((node.a, node.b, &node.value))
+    }
+}
+
+impl Merge for () {
+    fn merge_left(&mut self, _lhs: ()) {}
+
+    fn merge_right(&mut self, _rhs: ()) {}
+}
+
 impl<K,V> Node<K,V>
 where
     K: Eq + Ord + Copy,
@@ -556,19 +670,13 @@ where
             l: None, r: None, p: None
         }
     }
-
-    /// Look for an overlapping interval [a,b) in this node and its
-    /// children.  If there is more than one interval overlapping
-    /// [a,b), only the first one is returned.  `index` is the index
-    /// of self in `tree`.
-    fn find(&self, index: Index, tree: &Slab<Node<K,V>>, a: K, b: K) -> FindResult {
-        unimplemented!()
-    }
 }
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "bench")]
     extern crate test;
+    #[cfg(feature = "bench")]
     use test::Bencher;
     use super::*;
 
@@ -584,7 +692,6 @@ mod tests {
 
     fn print_tree(t: &IntervalTree<i32, i32>, cur: Option<Index>, level: u32) {
         if let Some(cur) = cur {
-            let cur = cur as usize;
             print_tree(t, t.tree[cur].l, level + 1);
 
             (0..level).for_each(|_| print!("\t\t"));
@@ -603,7 +710,7 @@ mod tests {
         for (i, node) in t.tree.iter() {
             // Root check
             if node.p.is_none() {
-                assert_eq!(found_root, false);
+                assert!(!found_root);
                 assert_eq!(t.root, Some(i as Index));
                 assert_eq!(node.color, Black);
                 found_root = true;
@@ -627,7 +734,7 @@ mod tests {
                     println!("===================");
                     println!("Sanity check failed");
                     println!("===================");
-                    print_tree(&t, t.root, 0);
+                    print_tree(t, t.root, 0);
                     panic!("A red node has a red child");
                 }
             }
@@ -641,7 +748,7 @@ mod tests {
             }
             assert_eq!(node.m, m);
         }
-        assert_eq!(found_root, true);
+        assert!(found_root);
     }
 
     fn count_black<K,V>(t: &IntervalTree<K,V>, cur: Option<Index>) -> u32
@@ -727,6 +834,7 @@ mod tests {
         sanity_check(&t);
     }
 
+    #[cfg(feature = "bench")]
     #[bench]
     fn rb_insert_bench(b: &mut Bencher) {
         let mut t = IntervalTree::new();
@@ -737,6 +845,7 @@ mod tests {
         });
     }
 
+    #[cfg(feature = "bench")]
     #[bench]
     fn rb_insert_after_100k(b: &mut Bencher) {
         let mut t = IntervalTree::new();
@@ -813,8 +922,62 @@ mod tests {
                     t.remove(idx);
                     sanity_check(&t);
                 }
-                e @ _ => { panic!("{:?}", e) }
+                e => { panic!("{:?}", e) }
             }
         }
     }
+
+    #[test]
+    fn rb_remove_rand_1k() {
+        use rand::prelude::*;
+        use rand::seq::SliceRandom;
+
+        let mut rng = thread_rng();
+        let mut xs: Vec<_> = (0..1000).collect();
+        xs.shuffle(&mut rng);
+
+        let mut t = IntervalTree::new();
+        for &x in &xs {
+            t.insert_nonoverlapping(x * 2, x * 2 + 1, x);
+        }
+        xs.shuffle(&mut rng);
+        for (i, &x) in xs.iter().enumerate() {
+            match t.find(x * 2, x * 2 + 1) {
+                FindResult::Equal(idx) => assert_eq!(t.remove(idx), (x * 2, x * 2 + 1, x)),
+                e => panic!("{:?}", e),
+            }
+            if i % 50 == 0 {
+                sanity_check(&t);
+            }
+        }
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn insert_merge() {
+        let mut t = IntervalTree::new();
+        t.insert(10, 20, 1);
+        t.insert(30, 40, 2);
+        t.insert(50, 60, 4);
+        t.insert(70, 80, 8);
+        sanity_check(&t);
+
+        // Overlapping on the left, and adjacent on the right.
+        t.insert(15, 30, 16);
+        sanity_check(&t);
+        assert_eq!(t.iter().map(|(a, b, &v)| (a, b, v)).collect::<Vec<_>>(),
+                   vec![(10, 40, 19), (50, 60, 4), (70, 80, 8)]);
+
+        // Covering several intervals.  Those inside are replaced.
+        t.insert(45, 75, 32);
+        sanity_check(&t);
+        assert_eq!(t.iter().map(|(a, b, &v)| (a, b, v)).collect::<Vec<_>>(),
+                   vec![(10, 40, 19), (45, 80, 40)]);
+
+        // Inside an interval.
+        t.insert(11, 12, 64);
+        assert_eq!(t.iter().map(|(a, b, &v)| (a, b, v)).collect::<Vec<_>>(),
+                   vec![(10, 40, 83), (45, 80, 40)]);
+        assert_eq!(t.find(40, 45), FindResult::Miss);
+    }
 }
@@ -10,24 +10,47 @@
 //! in order to provide a transparent interface.  It can be considered
 //! an advanced replacement of `BufReader` and/or `BufWriter`.
 //!
+//! Writes are cached in pages too.  The written bytes are tracked as
+//! dirty ranges, where adjacent ones are merged, and only they are
+//! written back.  Dirty ranges are always written back in offset order,
+//! on `flush`, or when a page holding some of them is evicted, in which
+//! case the ones before it go first.  So a file opened for appending
+//! can be wrapped as long as it's only written at its end.
+//!
+//! Reads which go on where the last one ended are sequential.  A miss
+//! of a sequential read reads the next pages ahead in one go, in
+//! chunks that double while the reads stay sequential.  Ranges known
+//! to be needed soon can be read ahead with `prefetch`.
+//!
 //! This mod is intentionally named "flex" instead of the more general
 //! "cache" to avoid confusion.
 
-#![allow(unused)]
+pub(crate) mod interval_tree;
+pub mod policy;
 
-mod interval_tree;
-
-use std::io::{Read, Write, Seek};
-use std::io::{SeekFrom, Result};
+use std::io::{IoSlice, Read, Write, Seek};
+use std::io::{SeekFrom, Result, Error, ErrorKind};
+use std::ops::Range;
 
 use bytes::BytesMut;
 
-use interval_tree::{Merge, Split};
+use interval_tree::{IntervalTree, FindResult, Index, Merge, Split};
+pub use policy::{Policy, EvictionPolicy};
 
 /// Default flex size.  This value is big enough to cover many common
 /// cases.
 pub const DEFAULT_FLEX_SIZE: usize = 64 * 1024 * 1024;
 
+/// Size of a page, the unit of caching and eviction.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Readahead window of the first miss of a sequential read.
+const MIN_READAHEAD: u64 = 4 * PAGE_SIZE;
+
+/// The readahead window doubles on each miss of a sequential read, up
+/// to this size, or a quarter of the capacity if it's smaller.
+const MAX_READAHEAD: u64 = 64 * PAGE_SIZE;
+
 /// The Flexible cache.  The cache represent the latest view of the file.
 ///
 /// It wraps a `File`, which should be readable and writable.
@@ -42,6 +65,30 @@ where F: Read + Write + Seek
     /// structures.  Since they tend to be small, this should not be a
     /// problem anyway.
     capacity: usize,
+
+    /// Cached pages.  A page starts at a multiple of `PAGE_SIZE`, and
+    /// holds at most `PAGE_SIZE` bytes.  Bytes of the page after its
+    /// data and before the end of the file are zeros.  Its interval is
+    /// the whole page however much data it holds, so that it doesn't
+    /// change as the data grows.
+    pages: IntervalTree<u64, BytesMut>,
+    /// Chooses the pages to evict.
+    policy: Box<dyn Policy>,
+    /// Ranges of the data not written back yet.  Their pages are
+    /// cached.
+    dirty: IntervalTree<u64, ()>,
+    /// Number of bytes of cached data.
+    used: usize,
+    /// Length of the file, including the data not written back yet.
+    /// It's found when it's first needed.
+    len: Option<u64>,
+    /// Current position.
+    pos: u64,
+    /// Where the last read ended.  A read starting there is
+    /// sequential.
+    last_read: u64,
+    /// Bytes read ahead on the last miss of a sequential read, or 0.
+    readahead: u64,
 }
 
 impl<F> Flex<F>
@@ -52,9 +99,221 @@ where F: Read + Write + Seek
     }
 
     pub fn with_capacity(capacity: usize, file: F) -> Flex<F> {
+        Flex::with_policy(capacity, EvictionPolicy::default().build(), file)
+    }
+
+    pub fn with_policy(capacity: usize, policy: Box<dyn Policy>, file: F) -> Flex<F> {
         Flex {
             file,
             capacity,
+            pages: IntervalTree::new(),
+            policy,
+            dirty: IntervalTree::new(),
+            used: 0,
+            len: None,
+            pos: 0,
+            last_read: 0,
+            readahead: 0,
+        }
+    }
+
+    /// Get a reference to the underlying file.  It doesn't have the
+    /// data not written back yet.
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    /// Number of bytes of cached data.
+    pub fn cached(&self) -> usize {
+        self.used
+    }
+
+    /// Change the capacity, evicting pages until the cached data fits
+    /// in it.
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<()> {
+        self.capacity = capacity;
+        self.evict(0)
+    }
+
+    /// Forget the cached pages overlapping [start, end), and the
+    /// length of the file, after the file is changed behind the cache.
+    /// Their data not written back yet is discarded.
+    pub fn invalidate(&mut self, start: u64, end: u64) {
+        // The page holding `start` may end right before it, and be
+        // short because it was at the end of the file.
+        let start = start - start % PAGE_SIZE;
+        while let Some(n) = self.pages.find(start, end).index() {
+            let (page, page_end, data) = self.pages.remove(n);
+            self.used -= data.len();
+            self.clean(page, page_end);
+            self.policy.remove(page);
+        }
+        self.len = None;
+    }
+
+    /// Read the pages overlapping `range` into the cache ahead of
+    /// their use, as far as they fit in the capacity.  Cached pages
+    /// are kept as they are, and the part of `range` past the end of
+    /// the file is ignored.
+    pub fn prefetch(&mut self, range: Range<u64>) -> Result<()> {
+        let start = range.start - range.start % PAGE_SIZE;
+        let end = range.end.min(start.saturating_add(self.capacity as u64));
+        self.fill(start, end)
+    }
+
+    fn len(&mut self) -> Result<u64> {
+        if let Some(len) = self.len {
+            return Ok(len)
+        }
+        let len = self.file.seek(SeekFrom::End(0))?;
+        self.len = Some(len);
+        Ok(len)
+    }
+
+    /// Find the cached page starting at `start`.
+    fn find_page(&self, start: u64) -> Option<Index> {
+        match self.pages.find(start, start + PAGE_SIZE) {
+            FindResult::Miss => None,
+            FindResult::Equal(n) => Some(n),
+            // Pages are aligned, and their intervals are whole pages.
+            _ => unreachable!(),
+        }
+    }
+
+    /// Find the page starting at `start`, which must be before the end
+    /// of the file, and read it from the file if it's not cached.
+    fn page(&mut self, start: u64) -> Result<Index> {
+        if let Some(n) = self.find_page(start) {
+            self.policy.access(start);
+            return Ok(n)
+        }
+        let len = (self.len()? - start).min(PAGE_SIZE) as usize;
+        self.evict(len)?;
+        let mut data = BytesMut::with_capacity(PAGE_SIZE as usize);
+        data.resize(len, 0);
+        self.file.seek(SeekFrom::Start(start))?;
+        read_full(&mut self.file, &mut data)?;
+        self.pages.insert_nonoverlapping(start, start + PAGE_SIZE, data);
+        self.policy.insert(start);
+        self.used += len;
+        Ok(self.find_page(start).unwrap())
+    }
+
+    /// Read the pages in [start, end) which are not cached, where
+    /// `start` is the start of a page.  Each run of consecutive missing
+    /// pages is read from the file at once.
+    fn fill(&mut self, start: u64, end: u64) -> Result<()> {
+        let len = self.len()?;
+        let end = end.min(len);
+        let mut page = start;
+        while page < end {
+            if self.find_page(page).is_some() {
+                page += PAGE_SIZE;
+                continue
+            }
+            let mut run_end = page + PAGE_SIZE;
+            while run_end < end && self.find_page(run_end).is_none() {
+                run_end += PAGE_SIZE;
+            }
+            let run_end = run_end.min(len);
+            let run_len = (run_end - page) as usize;
+            self.evict(run_len)?;
+            let mut data = vec![0; run_len];
+            self.file.seek(SeekFrom::Start(page))?;
+            read_full(&mut self.file, &mut data)?;
+            // Each page gets its own buffer, so that evicting it frees
+            // its memory.
+            for chunk in data.chunks(PAGE_SIZE as usize) {
+                let mut page_data = BytesMut::with_capacity(PAGE_SIZE as usize);
+                page_data.extend_from_slice(chunk);
+                self.pages.insert_nonoverlapping(page, page + PAGE_SIZE, page_data);
+                self.policy.insert(page);
+                page += PAGE_SIZE;
+            }
+            self.used += run_len;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes to read ahead on a miss of a read at the
+    /// current position, which grows as long as reads are sequential.
+    fn next_readahead(&mut self) -> u64 {
+        let max = MAX_READAHEAD.min(self.capacity as u64 / 4);
+        self.readahead = if self.pos != self.last_read || max < MIN_READAHEAD {
+            0
+        } else {
+            (self.readahead * 2).clamp(MIN_READAHEAD, max)
+        };
+        self.readahead
+    }
+
+    /// Evict pages until `room` more bytes fit in the capacity, writing
+    /// back dirty ones.
+    fn evict(&mut self, room: usize) -> Result<()> {
+        while self.used + room > self.capacity {
+            let start = match self.policy.evict() {
+                Some(start) => start,
+                None => break,
+            };
+            let end = start + PAGE_SIZE;
+            if self.dirty.find(start, end).index().is_some() {
+                self.write_back(end)?;
+            }
+            let (_, _, data) = self.pages.remove(self.find_page(start).unwrap());
+            self.used -= data.len();
+        }
+        Ok(())
+    }
+
+    /// Write back the dirty ranges which start before `end`, in offset
+    /// order.
+    fn write_back(&mut self, end: u64) -> Result<()> {
+        while let Some((start, range_end, _)) = self.dirty.iter().next() {
+            if start >= end {
+                break
+            }
+            self.write_range(start, range_end)?;
+            let n = self.dirty.find(start, range_end).index().unwrap();
+            self.dirty.remove(n);
+        }
+        Ok(())
+    }
+
+    /// Write the cached data of [start, end) to the file, gathering
+    /// the pages into one vectored write.
+    fn write_range(&mut self, start: u64, end: u64) -> Result<()> {
+        self.file.seek(SeekFrom::Start(start))?;
+        let mut pos = start;
+        while pos < end {
+            // Start over from `pos` after a partial write.
+            let mut slices = Vec::new();
+            let mut chunk_start = pos;
+            while chunk_start < end {
+                let page = chunk_start - chunk_start % PAGE_SIZE;
+                let (_, _, data) = self.pages.get(self.find_page(page).unwrap());
+                let chunk_end = (page + data.len() as u64).min(end);
+                debug_assert!(chunk_end > chunk_start);
+                slices.push(IoSlice::new(&data[(chunk_start - page) as usize..(chunk_end - page) as usize]));
+                chunk_start = chunk_end;
+            }
+            match self.file.write_vectored(&slices)? {
+                0 => return Err(Error::new(ErrorKind::WriteZero, "failed to write back dirty data")),
+                n => pos += n as u64,
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the dirty ranges within [start, end).
+    fn clean(&mut self, start: u64, end: u64) {
+        while let Some(n) = self.dirty.find(start, end).index() {
+            let (range_start, range_end, _) = self.dirty.remove(n);
+            if range_start < start {
+                self.dirty.insert_nonoverlapping(range_start, start, ());
+            }
+            if range_end > end {
+                self.dirty.insert_nonoverlapping(end, range_end, ());
+            }
         }
     }
 }
@@ -63,7 +322,33 @@ impl<F> Read for Flex<F>
 where F: Read + Write + Seek
 {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        unimplemented!()
+        let len = self.len()?;
+        if buf.is_empty() || self.pos >= len {
+            return Ok(0)
+        }
+        let start = self.pos - self.pos % PAGE_SIZE;
+        if self.find_page(start).is_none() {
+            let readahead = self.next_readahead();
+            if readahead > 0 {
+                self.fill(start, start + readahead)?;
+            }
+        }
+        let n = self.page(start)?;
+        let (_, _, data) = self.pages.get(n);
+        let off = (self.pos - start) as usize;
+        let count = if off < data.len() {
+            let count = buf.len().min(data.len() - off);
+            buf[..count].copy_from_slice(&data[off..off + count]);
+            count
+        } else {
+            let end = (len - start).min(PAGE_SIZE) as usize;
+            let count = buf.len().min(end - off);
+            buf[..count].iter_mut().for_each(|b| *b = 0);
+            count
+        };
+        self.pos += count as u64;
+        self.last_read = self.pos;
+        Ok(count)
     }
 }
 
@@ -74,12 +359,39 @@ where F: Read + Write + Seek
     /// The written data is aggressively cached, until (some portions
     /// of) it is evicted, or `flush()` is called.
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        unimplemented!()
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let len = self.len()?;
+        let start = self.pos - self.pos % PAGE_SIZE;
+        let off = (self.pos - start) as usize;
+        let count = buf.len().min(PAGE_SIZE as usize - off);
+
+        let n = if start < len {
+            self.page(start)?
+        } else {
+            self.evict(off + count)?;
+            self.pages.insert_nonoverlapping(start, start + PAGE_SIZE, BytesMut::with_capacity(PAGE_SIZE as usize));
+            self.policy.insert(start);
+            self.find_page(start).unwrap()
+        };
+        let (_, _, data) = self.pages.get_mut(n);
+        if data.len() < off + count {
+            self.used += off + count - data.len();
+            data.resize(off + count, 0);
+        }
+        data[off..off + count].copy_from_slice(&buf[..count]);
+        self.dirty.insert(self.pos, self.pos + count as u64, ());
+
+        self.pos += count as u64;
+        self.len = Some(len.max(self.pos));
+        Ok(count)
     }
 
     /// Make sure the dirty data in the cache is flushed to the disk.
     fn flush(&mut self) -> Result<()> {
-        unimplemented!()
+        self.write_back(u64::MAX)?;
+        self.file.flush()
     }
 }
 
@@ -87,17 +399,62 @@ impl<F> Seek for Flex<F>
 where F: Read + Write + Seek
 {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        unimplemented!()
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(delta) => add_offset(self.len()?, delta),
+            SeekFrom::Current(delta) => add_offset(self.pos, delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
     }
 }
 
+impl<F> Drop for Flex<F>
+where F: Read + Write + Seek
+{
+    /// Dirty data is written back, and errors are ignored, like
+    /// `BufWriter` does.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn add_offset(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+/// Read from `file` until `buf` is full or the end of the file, and
+/// fill the rest with zeros.
+fn read_full<R: Read>(file: &mut R, mut buf: &mut [u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match file.read(buf) {
+            Ok(0) => break,
+            Ok(n) => buf = &mut buf[n..],
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    buf.iter_mut().for_each(|b| *b = 0);
+    Ok(())
+}
+
 impl Merge for BytesMut {
     fn merge_right(&mut self, rhs: BytesMut) {
         self.unsplit(rhs);
     }
 
     fn merge_left(&mut self, mut lhs: BytesMut) {
-        unimplemented!();
+        lhs.unsplit(self.take());
+        *self = lhs;
     }
 }
 
@@ -107,3 +464,215 @@ impl Split<u64> for BytesMut {
         (self, split)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_and_write() {
+        for &policy in &[EvictionPolicy::Lru, EvictionPolicy::Clock, EvictionPolicy::TwoQ] {
+            read_and_write_with(policy);
+        }
+    }
+
+    fn read_and_write_with(policy: EvictionPolicy) {
+        let file: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let mut flex = Flex::with_policy(2 * PAGE_SIZE as usize, policy.build(), Cursor::new(file.clone()));
+
+        let mut buf = vec![0; 5000];
+        flex.seek(SeekFrom::Start(3000)).unwrap();
+        flex.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &file[3000..8000]);
+        assert_eq!(flex.cached(), 2 * PAGE_SIZE as usize);
+
+        // Reading past the end gives a short read.
+        assert_eq!(flex.seek(SeekFrom::End(-10)).unwrap(), 9990);
+        assert_eq!(flex.read(&mut buf).unwrap(), 10);
+        assert_eq!(flex.read(&mut buf).unwrap(), 0);
+        assert!(flex.seek(SeekFrom::Current(-20000)).is_err());
+
+        // Writes are cached until they're flushed or evicted.
+        flex.seek(SeekFrom::Start(4090)).unwrap();
+        flex.write_all(b"0123456789").unwrap();
+        assert_eq!(&flex.get_ref().get_ref()[4090..4100], &file[4090..4100]);
+        flex.seek(SeekFrom::Start(4090)).unwrap();
+        flex.read_exact(&mut buf[..10]).unwrap();
+        assert_eq!(&buf[..10], b"0123456789");
+        flex.flush().unwrap();
+        assert_eq!(&flex.get_ref().get_ref()[4090..4100], b"0123456789");
+
+        // Appends and sparse writes extend the file.
+        flex.seek(SeekFrom::Start(20000)).unwrap();
+        flex.write_all(b"end").unwrap();
+        assert_eq!(flex.seek(SeekFrom::End(0)).unwrap(), 20003);
+        flex.seek(SeekFrom::Start(9998)).unwrap();
+        flex.read_exact(&mut buf[..5]).unwrap();
+        assert_eq!(&buf[..5], &[file[9998], file[9999], 0, 0, 0]);
+        for i in 0..3u64 {
+            flex.seek(SeekFrom::Start(i * PAGE_SIZE)).unwrap();
+            flex.write_all(b"page").unwrap();
+        }
+        assert!(flex.cached() <= 2 * PAGE_SIZE as usize);
+        drop(flex);
+    }
+
+    #[test]
+    fn invalidate() {
+        let mut flex = Flex::with_capacity(4 * PAGE_SIZE as usize, Cursor::new(vec![1; 6000]));
+        let mut buf = vec![0; 6000];
+        flex.read_exact(&mut buf).unwrap();
+
+        // The file grows and changes behind the cache.
+        let file = flex.file.get_mut();
+        file.extend_from_slice(&[2; 100]);
+        file[10] = 3;
+        flex.invalidate(6000, u64::MAX);
+        assert_eq!(flex.cached(), PAGE_SIZE as usize);
+        flex.seek(SeekFrom::Start(5990)).unwrap();
+        flex.read_exact(&mut buf[..20]).unwrap();
+        assert_eq!(&buf[..20], &[[1; 10], [2; 10]].concat()[..]);
+        assert_eq!(flex.seek(SeekFrom::End(0)).unwrap(), 6100);
+
+        flex.seek(SeekFrom::Start(10)).unwrap();
+        assert_eq!(flex.read(&mut buf[..1]).unwrap(), 1);
+        assert_eq!(buf[0], 1);
+        flex.invalidate(10, 11);
+        flex.seek(SeekFrom::Start(10)).unwrap();
+        assert_eq!(flex.read(&mut buf[..1]).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+    }
+
+    /// Counts the reads of the wrapped file, and records its writes.
+    struct Counted {
+        file: Cursor<Vec<u8>>,
+        reads: usize,
+        writes: Vec<(u64, usize)>,
+    }
+
+    impl Counted {
+        fn new(file: Vec<u8>) -> Counted {
+            Counted { file: Cursor::new(file), reads: 0, writes: Vec::new() }
+        }
+    }
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.reads += 1;
+            self.file.read(buf)
+        }
+    }
+
+    impl Write for Counted {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.writes.push((self.file.position(), buf.len()));
+            self.file.write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+            self.writes.push((self.file.position(), bufs.iter().map(|buf| buf.len()).sum()));
+            self.file.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Counted {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    #[test]
+    fn readahead() {
+        let file: Vec<u8> = (0..1000000u32).map(|i| i as u8).collect();
+        let mut flex = Flex::with_capacity(1 << 20, Counted::new(file.clone()));
+
+        // A sequential scan reads ahead in growing chunks: 4, 8, 16,
+        // 32 and then 64 pages.
+        let mut buf = vec![0; 100];
+        for i in 0..3000 {
+            flex.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &file[i * 100..i * 100 + 100]);
+        }
+        assert_eq!(flex.file.reads, 5);
+        assert_eq!(flex.cached(), 124 * PAGE_SIZE as usize);
+
+        // Random reads don't.
+        flex.file.reads = 0;
+        for &pos in &[900000, 600000, 800000] {
+            flex.seek(SeekFrom::Start(pos)).unwrap();
+            flex.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &file[pos as usize..pos as usize + 100]);
+        }
+        assert_eq!(flex.file.reads, 3);
+        assert_eq!(flex.cached(), 127 * PAGE_SIZE as usize);
+
+        // Prefetching reads the missing pages of a range, a run at a
+        // time, and stops at the end of the file.
+        flex.file.reads = 0;
+        flex.prefetch(590000..2000000).unwrap();
+        assert_eq!(flex.file.reads, 4);
+        assert_eq!(flex.cached(), 1000000 - 589824 + 124 * PAGE_SIZE as usize);
+        flex.seek(SeekFrom::Start(999950)).unwrap();
+        assert_eq!(flex.read(&mut buf).unwrap(), 50);
+        assert_eq!(&buf[..50], &file[999950..]);
+        assert_eq!(flex.file.reads, 4);
+    }
+
+    #[test]
+    fn dirty_ranges() {
+        let mut flex = Flex::with_capacity(2 * PAGE_SIZE as usize, Counted::new(vec![1; 3 * PAGE_SIZE as usize]));
+
+        // Adjacent and overlapping writes are merged, and only the
+        // written bytes are written back.
+        for &(pos, data) in &[(10, &b"ab"[..]), (12, b"cd"), (11, b"BC"), (5000, b"x")] {
+            flex.seek(SeekFrom::Start(pos)).unwrap();
+            flex.write_all(data).unwrap();
+        }
+        flex.flush().unwrap();
+        assert_eq!(flex.file.writes, vec![(10, 4), (5000, 1)]);
+        assert_eq!(&flex.file.file.get_ref()[9..15], b"\x01aBCd\x01");
+
+        // A dirty range across pages is written back at once.
+        flex.file.writes.clear();
+        flex.seek(SeekFrom::Start(100)).unwrap();
+        flex.write_all(&[2; PAGE_SIZE as usize]).unwrap();
+        flex.flush().unwrap();
+        assert_eq!(flex.file.writes, vec![(100, PAGE_SIZE as usize)]);
+
+        // Evicting a dirty page writes back the dirty ranges before it
+        // first.
+        let mut flex = Flex::with_capacity(2 * PAGE_SIZE as usize, Counted::new(vec![1; 3 * PAGE_SIZE as usize]));
+        for &page in &[1, 0, 2] {
+            flex.seek(SeekFrom::Start(page * PAGE_SIZE + 100)).unwrap();
+            flex.write_all(b"page").unwrap();
+        }
+        assert_eq!(flex.file.writes, vec![(100, 4), (PAGE_SIZE + 100, 4)]);
+        flex.file.writes.clear();
+        flex.flush().unwrap();
+        assert_eq!(flex.file.writes, vec![(2 * PAGE_SIZE + 100, 4)]);
+
+        // Invalidated dirty data is discarded.
+        flex.seek(SeekFrom::Start(200)).unwrap();
+        flex.write_all(&[9; PAGE_SIZE as usize]).unwrap();
+        flex.invalidate(PAGE_SIZE, u64::MAX);
+        flex.flush().unwrap();
+        let file = flex.file.file.get_ref();
+        assert_eq!(&file[200..PAGE_SIZE as usize], &[9; PAGE_SIZE as usize - 200][..]);
+        assert_eq!(file[PAGE_SIZE as usize], 1);
+    }
+
+    #[test]
+    fn write_back_on_drop() {
+        let mut file = Vec::new();
+        {
+            let mut flex = Flex::with_capacity(PAGE_SIZE as usize, Cursor::new(&mut file));
+            flex.write_all(&[7; 10000]).unwrap();
+        }
+        assert_eq!(file, vec![7; 10000]);
+    }
+}
@@ -0,0 +1,311 @@
+//! # Eviction policies
+//!
+//! This mod contains the policies a `Flex` uses to choose which page
+//! to evict when it's full.
+//!
+//! * `Lru` evicts the least recently used page.
+//! * `Clock` approximates LRU with a reference bit per page, which is
+//!   cheaper to keep up to date.
+//! * `TwoQ` is scan resistant.  Pages used once, like those of a large
+//!   sequential scan, are evicted first, and don't push out the pages
+//!   used again and again, like those of hot point lookups.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// An eviction policy, which tracks the cached pages by their start
+/// offsets.
+pub trait Policy: Send {
+    /// A page is cached.
+    fn insert(&mut self, page: u64);
+
+    /// A cached page is used again.
+    fn access(&mut self, page: u64);
+
+    /// A cached page is dropped other than by `evict`.
+    fn remove(&mut self, page: u64);
+
+    /// Choose a cached page to evict, and forget it.
+    fn evict(&mut self) -> Option<u64>;
+}
+
+/// The built-in eviction policies.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// See `Lru`.
+    Lru,
+    /// See `Clock`.
+    Clock,
+    /// See `TwoQ`.  This is the default.
+    #[default]
+    TwoQ,
+}
+
+impl EvictionPolicy {
+    /// Create a new instance of the policy.
+    pub fn build(self) -> Box<dyn Policy> {
+        match self {
+            EvictionPolicy::Lru => Box::new(Lru::default()),
+            EvictionPolicy::Clock => Box::new(Clock::default()),
+            EvictionPolicy::TwoQ => Box::new(TwoQ::default()),
+        }
+    }
+}
+
+/// Evict the least recently used page.
+#[derive(Default)]
+pub struct Lru {
+    /// Pages with their last access time.
+    pages: HashMap<u64, u64>,
+    /// Pages ordered by their last access time.
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl Policy for Lru {
+    fn insert(&mut self, page: u64) {
+        self.access(page);
+    }
+
+    fn access(&mut self, page: u64) {
+        self.clock += 1;
+        if let Some(used) = self.pages.insert(page, self.clock) {
+            self.lru.remove(&used);
+        }
+        self.lru.insert(self.clock, page);
+    }
+
+    fn remove(&mut self, page: u64) {
+        if let Some(used) = self.pages.remove(&page) {
+            self.lru.remove(&used);
+        }
+    }
+
+    fn evict(&mut self) -> Option<u64> {
+        let oldest = *self.lru.keys().next()?;
+        let page = self.lru.remove(&oldest).unwrap();
+        self.pages.remove(&page);
+        Some(page)
+    }
+}
+
+/// Evict the first page without its reference bit set, going round
+/// the pages in the order they're cached, and clearing the reference
+/// bits passed by.
+#[derive(Default)]
+pub struct Clock {
+    /// Pages with their reference bits.  Slots of removed pages are
+    /// reused.
+    ring: Vec<Option<(u64, bool)>>,
+    /// Slots of the pages.
+    slots: HashMap<u64, usize>,
+    /// Unused slots.
+    free: Vec<usize>,
+    hand: usize,
+}
+
+impl Policy for Clock {
+    fn insert(&mut self, page: u64) {
+        if self.slots.contains_key(&page) {
+            return self.access(page)
+        }
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.ring[slot] = Some((page, false));
+                slot
+            }
+            None => {
+                self.ring.push(Some((page, false)));
+                self.ring.len() - 1
+            }
+        };
+        self.slots.insert(page, slot);
+    }
+
+    fn access(&mut self, page: u64) {
+        if let Some(&slot) = self.slots.get(&page) {
+            self.ring[slot] = Some((page, true));
+        }
+    }
+
+    fn remove(&mut self, page: u64) {
+        if let Some(slot) = self.slots.remove(&page) {
+            self.ring[slot] = None;
+            self.free.push(slot);
+        }
+    }
+
+    fn evict(&mut self) -> Option<u64> {
+        if self.slots.is_empty() {
+            return None
+        }
+        loop {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % self.ring.len();
+            match &mut self.ring[slot] {
+                Some((_, referenced)) if *referenced => *referenced = false,
+                Some((page, _)) => {
+                    let page = *page;
+                    self.remove(page);
+                    return Some(page)
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+/// The 2Q policy, as simplified by Johnson and Shasha.  New pages
+/// enter a FIFO queue, and are evicted from there unless they're used
+/// again after they're evicted, while they're still remembered in a
+/// ghost queue.  Such pages move to an LRU queue of hot pages.
+///
+/// Uses of a page while it's in the FIFO queue don't count, so that
+/// the consecutive reads of a scan don't make its pages hot.
+#[derive(Default)]
+pub struct TwoQ {
+    /// Pages used once, oldest first.
+    a1in: VecDeque<u64>,
+    /// Pages evicted from `a1in`, oldest first.  They're not cached.
+    a1out: VecDeque<u64>,
+    a1out_set: HashSet<u64>,
+    /// Hot pages.
+    am: Lru,
+}
+
+impl TwoQ {
+    fn resident(&self) -> usize {
+        self.a1in.len() + self.am.pages.len()
+    }
+}
+
+impl Policy for TwoQ {
+    fn insert(&mut self, page: u64) {
+        if self.a1out_set.remove(&page) {
+            self.a1out.retain(|&p| p != page);
+            self.am.insert(page);
+        } else {
+            self.a1in.push_back(page);
+        }
+    }
+
+    fn access(&mut self, page: u64) {
+        if self.am.pages.contains_key(&page) {
+            self.am.access(page);
+        }
+    }
+
+    fn remove(&mut self, page: u64) {
+        self.a1in.retain(|&p| p != page);
+        self.am.remove(page);
+    }
+
+    fn evict(&mut self) -> Option<u64> {
+        // A quarter of the cache is for new pages, and twice as many
+        // evicted ones are remembered.
+        let resident = self.resident();
+        if self.a1in.len() > resident / 4 || self.am.pages.is_empty() {
+            let page = self.a1in.pop_front()?;
+            self.a1out.push_back(page);
+            self.a1out_set.insert(page);
+            while self.a1out.len() > (resident / 2).max(1) {
+                let old = self.a1out.pop_front().unwrap();
+                self.a1out_set.remove(&old);
+            }
+            return Some(page)
+        }
+        self.am.evict()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut lru = Lru::default();
+        for page in 0..4 {
+            lru.insert(page);
+        }
+        lru.access(0);
+        lru.remove(1);
+        assert_eq!(lru.evict(), Some(2));
+        assert_eq!(lru.evict(), Some(3));
+        assert_eq!(lru.evict(), Some(0));
+        assert_eq!(lru.evict(), None);
+    }
+
+    #[test]
+    fn clock() {
+        let mut clock = Clock::default();
+        for page in 0..4 {
+            clock.insert(page);
+        }
+        clock.access(0);
+        clock.access(2);
+        assert_eq!(clock.evict(), Some(1));
+        assert_eq!(clock.evict(), Some(3));
+        clock.remove(2);
+        clock.insert(4);
+        assert_eq!(clock.evict(), Some(0));
+        assert_eq!(clock.evict(), Some(4));
+        assert_eq!(clock.evict(), None);
+    }
+
+    /// A cache of `capacity` pages, counting misses.
+    struct Cache<P: Policy> {
+        policy: P,
+        cached: HashSet<u64>,
+        capacity: usize,
+        misses: usize,
+    }
+
+    impl<P: Policy> Cache<P> {
+        fn get(&mut self, page: u64) {
+            if self.cached.contains(&page) {
+                return self.policy.access(page)
+            }
+            self.misses += 1;
+            if self.cached.len() == self.capacity {
+                self.cached.remove(&self.policy.evict().unwrap());
+            }
+            self.policy.insert(page);
+            self.cached.insert(page);
+        }
+    }
+
+    #[test]
+    fn two_q_resists_scans() {
+        let mut cache = Cache { policy: TwoQ::default(), cached: HashSet::new(), capacity: 8, misses: 0 };
+
+        // Pages become hot by being used again soon after they're
+        // evicted.
+        for round in 1..5 {
+            for page in 0..4 {
+                cache.get(page);
+            }
+            cache.get(100 * round);
+            cache.get(100 * round + 1);
+        }
+
+        // A long scan, reading each page a few times, only misses its
+        // own pages.
+        cache.misses = 0;
+        for page in 1000..2000 {
+            for _ in 0..3 {
+                cache.get(page);
+            }
+        }
+        for page in 0..4 {
+            cache.get(page);
+        }
+        assert_eq!(cache.misses, 1000);
+
+        // Unlike with LRU.
+        let mut cache = Cache { policy: Lru::default(), cached: HashSet::new(), capacity: 8, misses: 0 };
+        for page in (0..4).chain(1000..2000).chain(0..4) {
+            cache.get(page);
+        }
+        assert_eq!(cache.misses, 1008);
+    }
+}
@@ -0,0 +1,157 @@
+//! # Hole
+//!
+//! The hole mod tracks the dead byte ranges of a log file, which no
+//! readable row refers to anymore, and gives their disk space back by
+//! punching holes into the file.  The file keeps its size, and holes
+//! read back as zeros.
+//!
+//! On Linux, holes are punched with `fallocate(FALLOC_FL_PUNCH_HOLE)`.
+//! Elsewhere, and on file systems without support, dead ranges are
+//! just forgotten.
+//!
+//! Dead ranges are not stored, so after a reopen, ranges punched
+//! before are punched again.  That's harmless, and the released space
+//! is measured by the blocks allocated to the file, so it's not counted
+//! twice.
+
+use std::fs::File;
+use std::io::Result;
+use std::sync::Mutex;
+
+use crate::Offset;
+use crate::flex::interval_tree::IntervalTree;
+
+pub(crate) struct DeadRanges {
+    /// Dead ranges not punched out yet.  Overlapping and adjacent
+    /// ranges are merged.
+    pending: Mutex<IntervalTree<Offset, ()>>,
+}
+
+impl DeadRanges {
+    pub fn new() -> DeadRanges {
+        DeadRanges {
+            pending: Mutex::new(IntervalTree::new()),
+        }
+    }
+
+    /// Mark the bytes [start, end) dead.
+    pub fn add(&self, start: Offset, end: Offset) {
+        if start < end {
+            self.pending.lock().unwrap().insert(start, end, ());
+        }
+    }
+
+    /// Forget the dead ranges from `end` on, after the file is cut
+    /// there, since the bytes may be written again.
+    pub fn truncate(&self, end: Offset) {
+        let mut pending = self.pending.lock().unwrap();
+        let mut kept = IntervalTree::new();
+        for (start, stop, _) in pending.iter().filter(|&(start, _, _)| start < end) {
+            kept.insert_nonoverlapping(start, stop.min(end), ());
+        }
+        *pending = kept;
+    }
+
+    /// Punch all pending dead ranges out of `file`, and return the
+    /// number of bytes of disk space released.  Only whole blocks of
+    /// the file system are released, so it can be less than the dead
+    /// bytes.  Nobody else may write `file` meanwhile.
+    pub fn punch(&self, file: &File) -> Result<u64> {
+        let mut pending = self.pending.lock().unwrap();
+        let allocated = allocated_bytes(file)?;
+        for (start, end, _) in pending.iter() {
+            if !punch_hole(file, start, end - start)? {
+                break
+            }
+        }
+        *pending = IntervalTree::new();
+        Ok(allocated.saturating_sub(allocated_bytes(file)?))
+    }
+
+    /// Total number of dead bytes not punched out yet.
+    pub fn len(&self) -> u64 {
+        self.pending.lock().unwrap().iter().map(|(start, end, _)| end - start).sum()
+    }
+}
+
+/// Bytes of disk space allocated to `file`.
+#[cfg(unix)]
+fn allocated_bytes(file: &File) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(file.metadata()?.blocks() * 512)
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(_file: &File) -> Result<u64> {
+    Ok(0)
+}
+
+/// Punch a hole of `len` bytes at `offset` into `file`, keeping its
+/// size.  Returns false if it's not supported.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: Offset, len: u64) -> Result<bool> {
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) };
+    if ret == 0 {
+        return Ok(true)
+    }
+    let err = Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: Offset, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use positioned_io::ReadAt;
+    use crate::tests::*;
+
+    #[test]
+    fn punch() {
+        ensure_nonexistent("HOLE_hole");
+        let mut file = OpenOptions::new().create_new(true).read(true).write(true).open("HOLE_hole").unwrap();
+        file.write_all(&[1; 64 * 1024]).unwrap();
+
+        let dead = DeadRanges::new();
+        dead.add(0, 4096);
+        dead.add(4096, 16384);
+        dead.add(8192, 12288);
+        dead.add(40000, 60000);
+        assert_eq!(dead.len(), 16384 + 20000);
+        dead.truncate(50000);
+        assert_eq!(dead.len(), 16384 + 10000);
+
+        file.sync_all().unwrap();
+        let released = dead.punch(&file).unwrap();
+        assert_eq!(dead.len(), 0);
+        assert_eq!(fs::metadata("HOLE_hole").unwrap().len(), 64 * 1024);
+        if released > 0 {
+            assert!((16384..=16384 + 10000).contains(&released));
+            let mut buf = [1; 16385];
+            file.read_exact_at(0, &mut buf).unwrap();
+            assert!(buf[..16384].iter().all(|&b| b == 0));
+            assert_eq!(buf[16384], 1);
+        }
+
+        // Punching the same ranges again, as after a reopen, releases
+        // nothing more.
+        dead.add(0, 16384);
+        dead.add(40000, 50000);
+        assert_eq!(dead.punch(&file).unwrap(), 0);
+
+        fs::remove_file("HOLE_hole").unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+//! # Hooks
+//!
+//! The hooks mod keeps the callbacks registered on an engine around
+//! commits.  Pre-commit hooks see what a transaction is about to
+//! commit, and can veto it by returning an error.  Post-commit hooks
+//! see the rows of each transaction after they are durable.  Error
+//! hooks see the failures of the work done after a commit, which
+//! don't fail the commit itself.
+
+use std::collections::HashMap;
+use std::io::{Error, Result};
+use std::ops::Range;
+
+use crate::RowId;
+
+/// What a transaction is about to commit, as seen by pre-commit hooks.
+pub struct PendingCommit<'a> {
+    /// The row of the first appended entry.
+    pub first_row: RowId,
+    /// The appended entries, in row order.
+    pub entries: &'a [Box<[u8]>],
+    /// The rows to delete, in the order of `Transaction::delete`.
+    pub deletes: &'a [RowId],
+    /// The rows to update and their new versions, in the order of
+    /// `Transaction::update`.
+    pub updates: &'a [(RowId, &'a [u8])],
+    /// The info updates, as put by `Transaction::put_info`.
+    pub info_updates: &'a HashMap<Box<[u8]>, Box<[u8]>>,
+}
+
+type PreCommitHook = Box<dyn FnMut(&PendingCommit<'_>) -> Result<()> + Send>;
+type PostCommitHook = Box<dyn FnMut(Range<RowId>) + Send>;
+type ErrorHook = Box<dyn FnMut(&Error) + Send>;
+
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pre_commit: Vec<PreCommitHook>,
+    post_commit: Vec<PostCommitHook>,
+    error: Vec<ErrorHook>,
+}
+
+impl Hooks {
+    pub fn add_pre_commit(&mut self, hook: PreCommitHook) {
+        self.pre_commit.push(hook);
+    }
+
+    pub fn add_post_commit(&mut self, hook: PostCommitHook) {
+        self.post_commit.push(hook);
+    }
+
+    pub fn add_error(&mut self, hook: ErrorHook) {
+        self.error.push(hook);
+    }
+
+    /// Whether pre-commit hooks need the pending entries.
+    pub fn wants_entries(&self) -> bool {
+        !self.pre_commit.is_empty()
+    }
+
+    /// Run the pre-commit hooks in order, and stop at the first one
+    /// which vetoes the commit.
+    pub fn pre_commit(&mut self, pending: &PendingCommit<'_>) -> Result<()> {
+        for hook in &mut self.pre_commit {
+            hook(pending)?;
+        }
+        Ok(())
+    }
+
+    /// Run the post-commit hooks in order.
+    pub fn post_commit(&mut self, rows: Range<RowId>) {
+        for hook in &mut self.post_commit {
+            hook(rows.clone());
+        }
+    }
+
+    /// Report an error of the work after a commit: log it, and run
+    /// the error hooks in order.
+    pub fn error(&mut self, err: &Error) {
+        ::log::warn!("after commit: {}", err);
+        for hook in &mut self.error {
+            hook(err);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! # Compact index
+//!
+//! A compact index keeps all offsets in memory, but encodes them in
+//! blocks: each block stores its smallest offset, and the distance
+//! of every offset to it, using only as many bits as the largest
+//! distance in the block needs.  Since offsets grow slowly, a row
+//! of a dense log of small entries costs one or two bytes instead of
+//! eight.  Lookups are still O(1).
+
+use std::io::Result;
+
+use crate::Offset;
+use super::Map;
+
+/// Number of rows in a block.  A block of width `w` takes exactly `w`
+/// words.
+const BLOCK_ROWS: usize = 64;
+
+pub(super) struct Compact {
+    blocks: Vec<Block>,
+    /// Bit-packed distances of all full blocks.
+    words: Vec<u64>,
+    /// Offsets of the last block, which is not full yet.
+    tail: Vec<Offset>,
+}
+
+struct Block {
+    base: Offset,
+    width: u8,
+    /// Index of the first word of this block in `words`.
+    start: usize,
+}
+
+impl Compact {
+    pub fn new() -> Compact {
+        Compact {
+            blocks: Vec::new(),
+            words: Vec::new(),
+            tail: Vec::with_capacity(BLOCK_ROWS),
+        }
+    }
+
+    /// Approximate number of bytes used on the heap.
+    #[cfg(test)]
+    fn heap_size(&self) -> usize {
+        use std::mem::size_of;
+        self.blocks.capacity() * size_of::<Block>()
+            + self.words.capacity() * size_of::<u64>()
+            + self.tail.capacity() * size_of::<Offset>()
+    }
+
+    /// Move the full tail into a new block.
+    fn pack(&mut self) {
+        debug_assert_eq!(self.tail.len(), BLOCK_ROWS);
+        let base = *self.tail.iter().min().unwrap();
+        let max = self.tail.iter().map(|&offset| offset - base).max().unwrap();
+        let width = 64 - max.leading_zeros() as usize;
+
+        let start = self.words.len();
+        self.words.resize(start + width, 0);
+        for (i, &offset) in self.tail.iter().enumerate() {
+            set_bits(&mut self.words[start..], i * width, width, offset - base);
+        }
+        self.blocks.push(Block { base, width: width as u8, start });
+        self.tail.clear();
+    }
+
+    /// Decode all offsets of a block.
+    fn unpack(&self, block: &Block) -> Vec<Offset> {
+        (0..BLOCK_ROWS).map(|i| block.get(&self.words, i)).collect()
+    }
+}
+
+impl Block {
+    fn get(&self, words: &[u64], i: usize) -> Offset {
+        let width = self.width as usize;
+        self.base + get_bits(&words[self.start..self.start + width], i * width, width)
+    }
+}
+
+/// Read `width` bits starting from bit `pos`.
+fn get_bits(words: &[u64], pos: usize, width: usize) -> u64 {
+    if width == 0 {
+        return 0
+    }
+    let (word, shift) = (pos / 64, pos % 64);
+    let mut bits = words[word] >> shift;
+    if shift + width > 64 {
+        bits |= words[word + 1] << (64 - shift);
+    }
+    if width < 64 {
+        bits & ((1 << width) - 1)
+    } else {
+        bits
+    }
+}
+
+/// Write `width` bits starting from bit `pos`.  The bits must be
+/// zero before.
+fn set_bits(words: &mut [u64], pos: usize, width: usize, bits: u64) {
+    if width == 0 {
+        return
+    }
+    let (word, shift) = (pos / 64, pos % 64);
+    words[word] |= bits << shift;
+    if shift + width > 64 {
+        words[word + 1] |= bits >> (64 - shift);
+    }
+}
+
+impl Map for Compact {
+    fn len(&self) -> usize {
+        self.blocks.len() * BLOCK_ROWS + self.tail.len()
+    }
+
+    fn get(&self, row: usize) -> Result<Offset> {
+        let (block, i) = (row / BLOCK_ROWS, row % BLOCK_ROWS);
+        match self.blocks.get(block) {
+            Some(block) => Ok(block.get(&self.words, i)),
+            None => Ok(self.tail[i]),
+        }
+    }
+
+    fn extend(&mut self, offsets: &[Offset]) -> Result<()> {
+        for &offset in offsets {
+            self.tail.push(offset);
+            if self.tail.len() == BLOCK_ROWS {
+                self.pack();
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        let (block, i) = (len / BLOCK_ROWS, len % BLOCK_ROWS);
+        if block < self.blocks.len() {
+            self.tail = self.unpack(&self.blocks[block]);
+            self.words.truncate(self.blocks[block].start);
+            self.blocks.truncate(block);
+        }
+        self.tail.truncate(i);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    #[test]
+    fn bits() {
+        let mut words = vec![0; 3];
+        set_bits(&mut words, 60, 7, 0b1011001);
+        set_bits(&mut words, 67, 64, u64::MAX);
+        assert_eq!(get_bits(&words, 60, 7), 0b1011001);
+        assert_eq!(get_bits(&words, 67, 64), u64::MAX);
+        assert_eq!(get_bits(&words, 0, 60), 0);
+    }
+
+    #[test]
+    fn compact() {
+        let mut rng = thread_rng();
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        for _ in 0..10_000 {
+            offsets.push(offset);
+            offset += rng.gen_range(2, 100);
+        }
+
+        let mut map = Compact::new();
+        map.extend(&offsets[..5000]).unwrap();
+        map.extend(&offsets[5000..]).unwrap();
+        assert_eq!(map.len(), offsets.len());
+        for (row, &offset) in offsets.iter().enumerate() {
+            assert_eq!(map.get(row).unwrap(), offset);
+        }
+        assert!(map.heap_size() * 2 < offsets.len() * 8);
+
+        map.truncate(1000).unwrap();
+        map.truncate(1003).unwrap();
+        assert_eq!(map.len(), 1000);
+        map.extend(&offsets[1000..1100]).unwrap();
+        for (row, &offset) in offsets[..1100].iter().enumerate() {
+            assert_eq!(map.get(row).unwrap(), offset);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! # Memory-mapped index
+//!
+//! A mapped index maps the index file into memory, and decodes
+//! offsets lazily on lookup.  Opening it costs the same regardless of
+//! the number of rows, and the page cache of the OS decides what
+//! stays in memory.
+//!
+//! Touching a mapped page past the end of the file raises SIGBUS.  The
+//! writer only shrinks the file after unmapping the rows, but followers
+//! can't know in advance, so they check the length of the file before
+//! each read.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::size_of;
+use std::sync::Arc;
+use std::thread;
+
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::{Mmap, MmapOptions};
+
+use crate::Offset;
+use super::Map;
+
+/// Size of the pages touched by the warm-up thread.
+const WARM_UP_STRIDE: usize = 4096;
+
+pub(super) struct Mapped {
+    file: File,
+    len: usize,
+    /// Empty files cannot be mapped.
+    mmap: Option<Arc<Mmap>>,
+    /// Whether another process may shrink the file.
+    checked: bool,
+}
+
+impl Mapped {
+    /// Map the first `len` rows of `file`.  If another process may
+    /// shrink the file, it must be `checked`.
+    pub fn new(file: File, len: usize, checked: bool) -> Result<Mapped> {
+        let mut mapped = Mapped {
+            file,
+            len: 0,
+            mmap: None,
+            checked,
+        };
+        mapped.remap(len)?;
+        Ok(mapped)
+    }
+
+    /// Touch every page of the mapping in a background thread, so
+    /// that later lookups don't have to wait for the disk.
+    pub fn warm_up(&self) {
+        // Warming up is best effort.
+        let file = match (self.checked, self.file.try_clone()) {
+            (false, _) => None,
+            (true, Ok(file)) => Some(file),
+            (true, Err(_)) => return,
+        };
+        if let Some(mmap) = self.mmap.clone() {
+            thread::spawn(move || {
+                let mut sum = 0u8;
+                for i in (0..mmap.len()).step_by(WARM_UP_STRIDE) {
+                    if let Some(file) = &file {
+                        if file.metadata().map_or(true, |metadata| metadata.len() <= i as u64) {
+                            break
+                        }
+                    }
+                    sum = sum.wrapping_add(unsafe { std::ptr::read_volatile(&mmap[i]) });
+                }
+                sum
+            });
+        }
+    }
+
+    fn remap(&mut self, len: usize) -> Result<()> {
+        self.mmap = if len == 0 {
+            None
+        } else {
+            let mmap = unsafe {
+                MmapOptions::new()
+                    .len(len * size_of::<Offset>())
+                    .map(&self.file)?
+            };
+            Some(Arc::new(mmap))
+        };
+        self.len = len;
+        Ok(())
+    }
+}
+
+impl Map for Mapped {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, row: usize) -> Result<Offset> {
+        let mmap = self.mmap.as_ref().unwrap();
+        let start = row * size_of::<Offset>();
+        if self.checked && self.file.metadata()?.len() < (start + size_of::<Offset>()) as u64 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "the index file is truncated"))
+        }
+        Ok(LittleEndian::read_u64(&mmap[start..start + size_of::<Offset>()]))
+    }
+
+    fn extend(&mut self, offsets: &[Offset]) -> Result<()> {
+        self.remap(self.len + offsets.len())
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        if len < self.len {
+            self.remap(len)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::RowId;
+    use crate::index::{Index, IndexMode};
+    use crate::tests::*;
+
+    #[test]
+    fn open_and_extend() {
+        ensure_nonexistent("IDX_mapped");
+        let mode = IndexMode::Mapped { warm_up: true };
+
+        let mut idx = Index::open_with("IDX_mapped", mode, false).unwrap();
+        assert_eq!(idx.next_row(), 0);
+        assert_eq!(idx.get(0).unwrap(), None);
+        for batch in 0..3 {
+            let mut tx = idx.transaction();
+            for i in 0..1000 {
+                tx.append(batch * 1000 + i);
+            }
+            tx.commit().unwrap();
+        }
+        assert_eq!(idx.get(2999).unwrap(), Some(2999));
+        drop(idx);
+
+        let idx = Index::open_with("IDX_mapped", mode, false).unwrap();
+        assert_eq!(idx.next_row(), 3000);
+        for row in 0..3000 {
+            assert_eq!(idx.get(row as RowId).unwrap(), Some(row));
+        }
+
+        fs::remove_file("IDX_mapped").unwrap();
+    }
+
+    #[test]
+    fn shrunk_by_writer() {
+        ensure_nonexistent("IDX_mapped_shrunk");
+        let mode = IndexMode::Mapped { warm_up: false };
+        let mut idx = Index::open_with("IDX_mapped_shrunk", mode, false).unwrap();
+        let mut tx = idx.transaction();
+        for i in 0..3000 {
+            tx.append(i);
+        }
+        tx.commit().unwrap();
+
+        // A follower reading past the end of the shrunk file gets an
+        // error instead of a fault.
+        let follower = Index::open_with("IDX_mapped_shrunk", mode, true).unwrap();
+        assert_eq!(follower.get(2999).unwrap(), Some(2999));
+        idx.truncate(10).unwrap();
+        assert!(follower.get(2999).is_err());
+        assert_eq!(follower.get(9).unwrap(), Some(9));
+        follower.refresh().unwrap();
+        assert_eq!(follower.get(2999).unwrap(), None);
+
+        fs::remove_file("IDX_mapped_shrunk").unwrap();
+    }
+}
@@ -0,0 +1,653 @@
+//! # Index
+
+mod paged;
+mod mapped;
+mod compact;
+
+use std::fs::{self, OpenOptions, File};
+use std::path::{Path, PathBuf};
+use std::io::{Result, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::convert::TryInto;
+use std::sync::RwLock;
+
+use byteorder::LittleEndian;
+use byteorder::ByteOrder;
+use positioned_io::{ReadAt, WriteAt};
+
+use crate::{Log, RowId, Offset};
+use self::paged::Paged;
+use self::mapped::Mapped;
+use self::compact::Compact;
+
+/// How an `Index` keeps the committed offsets in memory.
+///
+/// Only `Mapped` opens in constant time; the other modes read the
+/// whole index file on open, so opening takes time linear in the
+/// number of rows.  The default is `Memory`, so the fast open is opt-in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IndexMode {
+    /// Load the whole index file into memory.  Lookups never touch
+    /// the disk, but every row costs 8 bytes of memory, and opening
+    /// reads the whole file.  This is the default.
+    #[default]
+    Memory,
+
+    /// Keep only the recently used pages of the index file in
+    /// memory, using at most `budget` bytes.  Missing pages are read
+    /// from the file on demand.
+    Paged { budget: usize },
+
+    /// Memory-map the index file, and decode offsets on lookup.
+    /// Opening takes constant time.  If `warm_up` is set, a
+    /// background thread reads the whole file into the page cache.
+    ///
+    /// A read-only index checks the length of the file before each
+    /// lookup, since the writer may shrink it by removing rows from
+    /// the end.  Rows removed since the last refresh fail to be looked
+    /// up.  A shrink right between the check and the lookup can still
+    /// fault with SIGBUS.
+    Mapped { warm_up: bool },
+
+    /// Load the whole index file into memory, in a compressed form.
+    /// Lookups never touch the disk, and a row takes about one or two
+    /// bytes for dense logs of small entries.
+    Compact,
+}
+
+/// Committed offsets, as kept in memory by an `Index`.
+///
+/// Offsets are always written to the index file before they are
+/// handed to a map, so a map is free to read them back from the file
+/// instead of keeping all of them around.
+trait Map: Send + Sync {
+    /// Number of committed rows.
+    fn len(&self) -> usize;
+
+    /// Get the offset of `row`, which must be less than `len()`.
+    fn get(&self, row: usize) -> Result<Offset>;
+
+    /// Append offsets which are already in the index file.  The map
+    /// is left as it was if it fails.
+    fn extend(&mut self, offsets: &[Offset]) -> Result<()>;
+
+    /// Forget all rows starting from `len`.
+    fn truncate(&mut self, len: usize) -> Result<()>;
+}
+
+impl Map for Vec<Offset> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn get(&self, row: usize) -> Result<Offset> {
+        Ok(self[row])
+    }
+
+    fn extend(&mut self, offsets: &[Offset]) -> Result<()> {
+        self.extend_from_slice(offsets);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.truncate(len);
+        Ok(())
+    }
+}
+
+/// Number of rows to read at a time, when the whole index file is
+/// loaded into a compressed map.
+const LOAD_CHUNK_ROWS: usize = 64 * 1024;
+
+/// Read the offsets of rows `[from, to)` from an index file.
+fn read_offsets(file: &File, from: usize, to: usize) -> Result<Vec<Offset>> {
+    let mut buf = vec![0; (to - from) * size_of::<Offset>()];
+    file.read_exact_at((from * size_of::<Offset>()) as u64, &mut buf)?;
+    let mut offsets = vec![0; to - from];
+    LittleEndian::read_u64_into(&buf, &mut offsets);
+    Ok(offsets)
+}
+
+/// Read the number of committed rows of a sparse index from its rows
+/// file, if it has been written.
+fn read_rows(path: &Path) -> Result<Option<usize>> {
+    match fs::read(path) {
+        Ok(buf) if buf.len() >= 8 => Ok(Some(LittleEndian::read_u64(&buf).try_into().unwrap())),
+        Ok(_) => Ok(None),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// In-memory representation of an index file.
+///
+/// Only committed offsets are kept in memory, so an `Index` can be
+/// shared between threads and looked up while a transaction is open.
+/// How much of the index is kept in memory depends on the
+/// `IndexMode`.
+///
+/// A sparse index only records the offset of every `stride`-th row,
+/// called a checkpoint.  Other rows are found by skipping entries in
+/// the log file, starting from the nearest checkpoint.  Its number
+/// of committed rows is kept in a separate rows file, so that
+/// entries of a transaction that hasn't committed yet, or never did,
+/// are not taken for rows.
+pub struct Index {
+    file: File,
+    committed: RwLock<Committed>,
+    read_only: bool,
+    stride: usize,
+    /// Path of the rows file of a sparse index.
+    rows_path: Option<PathBuf>,
+    /// The rows file of a writable sparse index.
+    rows_file: Option<File>,
+}
+
+/// The committed rows of an index.
+struct Committed {
+    /// Offsets of the checkpoints.
+    map: Box<dyn Map>,
+    /// Number of rows.  For a dense index, this is the same as the
+    /// number of checkpoints.
+    rows: usize,
+}
+
+impl Index {
+    /// This function opens an existing index file, or creates one if
+    /// it does not exist yet, in the default `IndexMode`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Index> {
+        Index::open_with(path, IndexMode::default(), false)
+    }
+
+    /// Open an existing index file for reading only.
+    ///
+    /// Another process may keep appending to the file.  Call
+    /// `refresh` to pick up the new rows.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Index> {
+        Index::open_with(path, IndexMode::default(), true)
+    }
+
+    /// Open an index file in the specified mode.
+    ///
+    /// A writable index file is created if it doesn't exist yet.
+    pub fn open_with<P: AsRef<Path>>(path: P, mode: IndexMode, read_only: bool) -> Result<Index> {
+        Index::open_at(path.as_ref(), None, mode, read_only, 1)
+    }
+
+    /// Open a sparse index file, which records the offset of every
+    /// `stride`-th row, with its rows file `rows_path`.  A stride of 1
+    /// means a dense index, which doesn't use the rows file.
+    ///
+    /// The number of rows of a sparse index is unknown until
+    /// `recover` is called.  Checkpoints written by a transaction
+    /// that didn't commit are dropped from a writable index.
+    pub fn open_sparse<P: AsRef<Path>, Q: AsRef<Path>>(path: P, rows_path: Q, mode: IndexMode, read_only: bool, stride: usize) -> Result<Index> {
+        let rows_path = if stride > 1 { Some(rows_path.as_ref()) } else { None };
+        Index::open_at(path.as_ref(), rows_path, mode, read_only, stride)
+    }
+
+    fn open_at(path: &Path, rows_path: Option<&Path>, mode: IndexMode, read_only: bool, stride: usize) -> Result<Index> {
+        assert!(stride > 0);
+        let file = if read_only {
+            OpenOptions::new()
+                .read(true)
+                .open(path)?
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .read(true)
+                .open(path)?
+        };
+
+        // Make sure the types match.  This can generate a compile-time error.
+        unsafe {
+            debug_assert!(std::mem::transmute::<Offset, u64>(0) == 0);
+        }
+
+        let rows_file = match rows_path {
+            Some(rows_path) if !read_only => Some(OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(rows_path)?),
+            _ => None,
+        };
+
+        let len: usize = file.metadata()?.len().try_into().unwrap();
+        let mut n_rows = len / size_of::<Offset>();
+        if let Some(rows) = rows_path.map(read_rows).transpose()?.flatten() {
+            let checkpoints = rows.div_ceil(stride);
+            if !read_only && n_rows > checkpoints {
+                file.set_len((checkpoints * size_of::<Offset>()) as u64)?;
+                file.sync_data()?;
+            }
+            n_rows = n_rows.min(checkpoints);
+        }
+        let map: Box<dyn Map> = match mode {
+            IndexMode::Memory => Box::new(read_offsets(&file, 0, n_rows)?),
+            IndexMode::Paged { budget } => Box::new(Paged::new(file.try_clone()?, n_rows, budget)),
+            IndexMode::Mapped { warm_up } => {
+                let mapped = Mapped::new(file.try_clone()?, n_rows, read_only)?;
+                if warm_up {
+                    mapped.warm_up();
+                }
+                Box::new(mapped)
+            }
+            IndexMode::Compact => {
+                let mut compact = Compact::new();
+                for from in (0..n_rows).step_by(LOAD_CHUNK_ROWS) {
+                    let to = n_rows.min(from + LOAD_CHUNK_ROWS);
+                    compact.extend(&read_offsets(&file, from, to)?)?;
+                }
+                Box::new(compact)
+            }
+        };
+
+        Ok(Index {
+            file,
+            committed: RwLock::new(Committed {
+                rows: if stride == 1 { n_rows } else { 0 },
+                map,
+            }),
+            read_only,
+            stride,
+            rows_path: rows_path.map(PathBuf::from),
+            rows_file,
+        })
+    }
+
+    /// Only every `stride`-th row is recorded in the index file.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Find the end of the last committed row in the log file.
+    ///
+    /// For a sparse index, this reads the number of committed rows
+    /// from the rows file, so it must be called after opening or
+    /// refreshing the index.  Anything in the log file after the
+    /// returned offset is not committed.
+    ///
+    /// A sparse index written before rows files existed has none yet.
+    /// The rows after its last checkpoint are then counted in the log
+    /// file, and a writable index records the count.
+    pub fn recover(&self, log: &Log) -> Result<Offset> {
+        let mut committed = self.committed.write().unwrap();
+        let rows = match &self.rows_path {
+            Some(path) => read_rows(path)?,
+            None => None,
+        };
+        let checkpoints = match rows {
+            // Another process may have committed more rows since the
+            // last refresh.  Only the ones covered by the checkpoints
+            // read so far are taken.
+            Some(rows) => committed.map.len().min(rows.div_ceil(self.stride)),
+            None => committed.map.len(),
+        };
+        if checkpoints == 0 {
+            committed.rows = 0;
+            return Ok(0)
+        }
+
+        let last = committed.map.get(checkpoints - 1)?;
+        if self.stride == 1 {
+            return log.skip_entries(last, 1)
+        }
+        let first = (checkpoints - 1) * self.stride;
+        match rows {
+            Some(rows) => {
+                let n = rows.min(checkpoints * self.stride) - first;
+                committed.rows = first + n;
+                log.skip_entries(last, n)
+            }
+            None => {
+                let (n, end) = log.scan(last, self.stride)?;
+                committed.rows = first + n;
+                self.write_rows(committed.rows)?;
+                Ok(end)
+            }
+        }
+    }
+
+    /// Read the offsets appended to a read-only index file by another
+    /// process since the last refresh, and return the number of new
+    /// offsets.
+    ///
+    /// A partially written offset at the end of the file is left for
+    /// the next refresh.  For a sparse index, `recover` must be
+    /// called afterwards.  This is a no-op for a writable index.
+    pub fn refresh(&self) -> Result<usize> {
+        if !self.read_only {
+            return Ok(0)
+        }
+
+        let mut committed = self.committed.write().unwrap();
+        let len: usize = self.file.metadata()?.len().try_into().unwrap();
+        let mut n_rows = len / size_of::<Offset>();
+        if let Some(path) = &self.rows_path {
+            // Checkpoints past the committed rows may be dropped and
+            // written again by the writer.
+            if let Some(rows) = read_rows(path)? {
+                n_rows = n_rows.min(rows.div_ceil(self.stride));
+            }
+        }
+        if n_rows < committed.map.len() {
+            // The writer truncated the tail.
+            committed.map.truncate(n_rows)?;
+            if self.stride == 1 {
+                committed.rows = n_rows;
+            }
+        }
+        if n_rows <= committed.map.len() {
+            return Ok(0)
+        }
+
+        let old_len = committed.map.len();
+        committed.map.extend(&read_offsets(&self.file, old_len, n_rows)?)?;
+        if self.stride == 1 {
+            committed.rows = n_rows;
+        }
+        Ok(n_rows - old_len)
+    }
+
+    /// Look up a row ID, and get its offset in the log file.
+    ///
+    /// Only committed rows can be found.  It may have to read the
+    /// index file, depending on the `IndexMode`.  A sparse index only
+    /// knows the offsets of checkpoints, and fails with `InvalidInput`
+    /// for other rows.  Use `locate` instead.
+    pub fn get(&self, row: RowId) -> Result<Option<Offset>> {
+        match self.locate(row)? {
+            Some((offset, 0)) => Ok(Some(offset)),
+            Some(_) => Err(Error::new(ErrorKind::InvalidInput, "not a checkpoint of the sparse index")),
+            None => Ok(None),
+        }
+    }
+
+    /// Look up a row ID, and get the offset of the nearest checkpoint
+    /// before it, together with the number of entries to skip from
+    /// there.
+    pub fn locate(&self, row: RowId) -> Result<Option<(Offset, usize)>> {
+        let row = row as usize;
+        let committed = self.committed.read().unwrap();
+        if row < committed.rows {
+            let offset = committed.map.get(row / self.stride)?;
+            Ok(Some((offset, row % self.stride)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Start a new transaction to append a bunch of new RowId-Offset
+    /// pairs to the index file.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        self.begin()
+    }
+
+    /// Start a new transaction through a shared reference.
+    ///
+    /// The caller must make sure there is at most one transaction at
+    /// a time.  Lookups are still possible during a transaction, but
+    /// they only see committed rows.
+    pub(crate) fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            next_row: self.next_row(),
+            index: self,
+            pending: Vec::new(),
+            appended: 0,
+        }
+    }
+
+    /// Current length of the index file.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Get the row ID for the next entry.
+    pub fn next_row(&self) -> RowId {
+        self.committed.read().unwrap().rows.try_into().unwrap()
+    }
+
+    /// Drop the rows from `rows` on, both in memory and in the index
+    /// file, and make it durable.
+    pub fn truncate(&self, rows: RowId) -> Result<()> {
+        let rows = rows as usize;
+        let mut committed = self.committed.write().unwrap();
+        let checkpoints = rows.div_ceil(self.stride);
+        committed.map.truncate(checkpoints)?;
+        committed.rows = committed.rows.min(rows);
+        self.write_rows(committed.rows)?;
+        self.file.set_len((checkpoints * size_of::<Offset>()) as u64)?;
+        self.file.sync_data()
+    }
+
+    /// Durably record the number of committed rows of a writable
+    /// sparse index.  The count fits in a single sector, so it's
+    /// either the old or the new one after a crash.
+    fn write_rows(&self, rows: usize) -> Result<()> {
+        if let Some(file) = &self.rows_file {
+            let mut buf = [0; 8];
+            LittleEndian::write_u64(&mut buf, rows as u64);
+            (&*file).write_all_at(0, &buf)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Try to write the new checkpoints into the file, then the
+    /// number of rows into the rows file, and then publish the new
+    /// rows to readers.
+    fn sync_data(&self, pending: Vec<Offset>, appended: usize) -> Result<()> {
+        let rows = self.committed.read().unwrap().rows + appended;
+        let written = self.write_checkpoints(&pending)
+            .and_then(|()| self.write_rows(rows));
+        let mut committed = self.committed.write().unwrap();
+        if let Err(e) = written.and_then(|()| committed.map.extend(&pending)) {
+            // Keep the file in line with the map, so that the next
+            // transaction appends where the map ends.
+            let _ = self.file.set_len((committed.map.len() * size_of::<Offset>()) as u64);
+            let _ = self.write_rows(committed.rows);
+            return Err(e)
+        }
+        committed.rows = rows;
+        Ok(())
+    }
+
+    fn write_checkpoints(&self, pending: &[Offset]) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(())
+        }
+        let mut file = &self.file;
+        file.seek(SeekFrom::End(0))?;
+
+        // FIXME: Inefficient.
+        let mut buf = Vec::new();
+        buf.resize(std::mem::size_of_val(pending), Default::default());
+        LittleEndian::write_u64_into(pending, buf.as_mut_slice());
+        file.write_all(buf.as_ref())?;
+        file.sync_data()
+    }
+}
+
+/// An atomic update to Index.
+///
+/// New offsets are buffered in the transaction, and become visible
+/// all at once when it commits.
+pub struct Transaction<'idx> {
+    index: &'idx Index,
+    next_row: RowId,
+    /// New checkpoints.
+    pending: Vec<Offset>,
+    appended: usize,
+}
+
+impl<'idx> Transaction<'idx> {
+    /// Append a new offset into the index, and get a new row ID.
+    pub fn append(&mut self, offset: Offset) -> RowId {
+        let row = self.next_row;
+        if (row as usize).is_multiple_of(self.index.stride) {
+            self.pending.push(offset);
+        }
+        self.next_row += 1;
+        self.appended += 1;
+        row
+    }
+
+    /// Commit the updates to the index file.
+    pub fn commit(self) -> Result<()> {
+        self.index.sync_data(self.pending, self.appended)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::tests::*;
+
+    #[test]
+    fn open() {
+        {
+            ensure_nonexistent("IDX1");
+            Index::open("IDX1").unwrap();
+        }
+        {
+            Index::open("IDX1").unwrap();
+        }
+        fs::remove_file("IDX1").unwrap();
+    }
+
+    #[test]
+    fn transaction() {
+        ensure_nonexistent("IDX2");
+        let row;
+        {
+            let mut idx = Index::open("IDX2").unwrap();
+            let mut tx = idx.transaction();
+            row = tx.append(12321);
+            tx.commit().unwrap();
+        }
+        {
+            let idx = Index::open("IDX2").unwrap();
+            assert_eq!(idx.get(row).unwrap().unwrap(), 12321);
+        }
+        fs::remove_file("IDX2").unwrap();
+    }
+
+    #[test]
+    fn refresh() {
+        ensure_nonexistent("IDX4");
+        let mut idx = Index::open("IDX4").unwrap();
+        let follower = Index::open_read_only("IDX4").unwrap();
+        assert_eq!(follower.refresh().unwrap(), 0);
+
+        let mut tx = idx.transaction();
+        tx.append(1);
+        tx.append(2);
+        tx.commit().unwrap();
+        assert_eq!(follower.get(0).unwrap(), None);
+        assert_eq!(follower.refresh().unwrap(), 2);
+        assert_eq!(follower.next_row(), 2);
+        assert_eq!(follower.get(1).unwrap(), Some(2));
+        assert_eq!(follower.refresh().unwrap(), 0);
+
+        fs::remove_file("IDX4").unwrap();
+    }
+
+    #[test]
+    fn sparse() {
+        ensure_nonexistent("IDX_sparse");
+        ensure_nonexistent("ROWS_sparse");
+        ensure_nonexistent("LOG_sparse");
+        let mut log = Log::open("LOG_sparse").unwrap();
+        let mut idx = Index::open_sparse("IDX_sparse", "ROWS_sparse", IndexMode::Memory, false, 4).unwrap();
+        assert_eq!(idx.recover(&log).unwrap(), 0);
+
+        let mut log_tx = log.transaction().unwrap();
+        let mut idx_tx = idx.transaction();
+        let mut offsets = vec![];
+        for i in 0..10u8 {
+            let offset = log_tx.append(&[i; 3]).unwrap();
+            assert_eq!(idx_tx.append(offset), i as RowId);
+            offsets.push(offset);
+        }
+        log_tx.commit().unwrap();
+        idx_tx.commit().unwrap();
+        assert_eq!(fs::metadata("IDX_sparse").unwrap().len(), 3 * 8);
+
+        drop(idx);
+        let idx = Index::open_sparse("IDX_sparse", "ROWS_sparse", IndexMode::Memory, false, 4).unwrap();
+        assert_eq!(idx.recover(&log).unwrap(), log.size().unwrap());
+        assert_eq!(idx.next_row(), 10);
+        assert_eq!(idx.locate(6).unwrap(), Some((offsets[4], 2)));
+        assert_eq!(idx.get(8).unwrap(), Some(offsets[8]));
+        assert!(idx.get(9).is_err());
+        assert_eq!(idx.locate(10).unwrap(), None);
+        drop(idx);
+
+        // Entries whose rows were never committed to the index, like
+        // after a crash between the two commits, are not rows.
+        let end = log.size().unwrap();
+        let mut log_tx = log.transaction().unwrap();
+        for i in 10..13u8 {
+            log_tx.append(&[i; 3]).unwrap();
+        }
+        log_tx.commit().unwrap();
+        let reader = Index::open_sparse("IDX_sparse", "ROWS_sparse", IndexMode::Memory, true, 4).unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(reader.recover(&log).unwrap(), end);
+        assert_eq!(reader.next_row(), 10);
+        let idx = Index::open_sparse("IDX_sparse", "ROWS_sparse", IndexMode::Memory, false, 4).unwrap();
+        assert_eq!(idx.recover(&log).unwrap(), end);
+        assert_eq!(idx.next_row(), 10);
+
+        // Without a rows file, the entries after the last checkpoint
+        // are counted.
+        drop(idx);
+        fs::remove_file("ROWS_sparse").unwrap();
+        let idx = Index::open_sparse("IDX_sparse", "ROWS_sparse", IndexMode::Memory, false, 4).unwrap();
+        assert_eq!(idx.recover(&log).unwrap(), log.skip_entries(end, 2).unwrap());
+        assert_eq!(idx.next_row(), 12);
+        assert_eq!(fs::read("ROWS_sparse").unwrap(), 12u64.to_le_bytes());
+
+        fs::remove_file("IDX_sparse").unwrap();
+        fs::remove_file("ROWS_sparse").unwrap();
+        fs::remove_file("LOG_sparse").unwrap();
+    }
+
+    #[test]
+    fn bulk_append() {
+        ensure_nonexistent("IDX3");
+
+        // Generate data
+        let n = 1_000_000;
+        let mut offsets = Vec::with_capacity(n);
+        for i in 0..n {
+            offsets.push(i as Offset);
+        }
+
+        // Bulk append
+        let mut idx = Index::open("IDX3").unwrap();
+        let mut tx = idx.transaction();
+        let rowids: Vec<_> = offsets.iter()
+            .map(|&x| tx.append(x))
+            .collect();
+        tx.commit().unwrap();
+
+        // Instantly verify
+        rowids.iter().map(|&x| idx.get(x).unwrap().unwrap())
+            .zip(&offsets)
+            .for_each(|(a, &b)| assert_eq!(a, b));
+
+        // Re-read and verify
+        drop(idx);
+        let idx = Index::open("IDX3").unwrap();
+        rowids.iter().map(|&x| idx.get(x).unwrap().unwrap())
+            .zip(&offsets)
+            .for_each(|(a, &b)| assert_eq!(a, b));
+
+        fs::remove_file("IDX3").unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! # Paged index
+//!
+//! A paged index keeps only the recently used pages of the index file
+//! in memory, within a fixed budget.  Pages are loaded on demand and
+//! evicted in LRU order.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Result;
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use crate::Offset;
+use super::{Map, read_offsets};
+
+/// Number of offsets in a page.
+const PAGE_ROWS: usize = 512;
+
+/// Number of bytes of a page.
+const PAGE_SIZE: usize = PAGE_ROWS * size_of::<Offset>();
+
+pub(super) struct Paged {
+    file: File,
+    len: usize,
+    cache: Mutex<Cache>,
+}
+
+/// Loaded pages, with their last access time.
+struct Cache {
+    pages: HashMap<usize, (Box<[Offset]>, u64)>,
+    /// Pages ordered by their last access time.
+    lru: BTreeMap<u64, usize>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl Paged {
+    /// Create a paged index of `len` rows over `file`, using at most
+    /// `budget` bytes for the pages.  At least one page is always
+    /// kept.
+    pub fn new(file: File, len: usize, budget: usize) -> Paged {
+        Paged {
+            file,
+            len,
+            cache: Mutex::new(Cache {
+                pages: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                capacity: (budget / PAGE_SIZE).max(1),
+            }),
+        }
+    }
+
+    /// Number of pages currently in memory.
+    #[cfg(test)]
+    fn loaded_pages(&self) -> usize {
+        self.cache.lock().unwrap().pages.len()
+    }
+}
+
+impl Map for Paged {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, row: usize) -> Result<Offset> {
+        let page = row / PAGE_ROWS;
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let now = cache.clock;
+
+        if let Some((offsets, used)) = cache.pages.get_mut(&page) {
+            let offset = offsets[row % PAGE_ROWS];
+            let old = std::mem::replace(used, now);
+            cache.lru.remove(&old);
+            cache.lru.insert(now, page);
+            return Ok(offset)
+        }
+
+        let from = page * PAGE_ROWS;
+        let to = self.len.min(from + PAGE_ROWS);
+        let offsets = read_offsets(&self.file, from, to)?.into_boxed_slice();
+        let offset = offsets[row % PAGE_ROWS];
+        while cache.pages.len() >= cache.capacity {
+            let oldest = *cache.lru.keys().next().unwrap();
+            let victim = cache.lru.remove(&oldest).unwrap();
+            cache.pages.remove(&victim);
+        }
+        cache.pages.insert(page, (offsets, now));
+        cache.lru.insert(now, page);
+        Ok(offset)
+    }
+
+    fn extend(&mut self, offsets: &[Offset]) -> Result<()> {
+        // The last page may be loaded while partially filled.
+        self.truncate(self.len)?;
+        self.len += offsets.len();
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        let cache = self.cache.get_mut().unwrap();
+        let first_stale = len / PAGE_ROWS;
+        let stale: Vec<_> = cache.pages.keys().cloned().filter(|&page| page >= first_stale).collect();
+        for page in stale {
+            let (_, used) = cache.pages.remove(&page).unwrap();
+            cache.lru.remove(&used);
+        }
+        self.len = self.len.min(len);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::RowId;
+    use crate::index::{Index, IndexMode};
+    use crate::tests::*;
+
+    #[test]
+    fn bounded() {
+        ensure_nonexistent("IDX_paged");
+        let n = 10 * PAGE_ROWS + 7;
+        let mode = IndexMode::Paged { budget: 2 * PAGE_SIZE };
+        {
+            let mut idx = Index::open_with("IDX_paged", mode, false).unwrap();
+            let mut tx = idx.transaction();
+            for i in 0..n {
+                tx.append(i as Offset * 3);
+            }
+            tx.commit().unwrap();
+        }
+
+        let mut idx = Index::open_with("IDX_paged", mode, false).unwrap();
+        for row in (0..n).chain((0..n).rev()) {
+            assert_eq!(idx.get(row as RowId).unwrap(), Some(row as Offset * 3));
+        }
+        assert_eq!(idx.get(n as RowId).unwrap(), None);
+
+        // The partially filled last page picks up new rows.
+        let mut tx = idx.transaction();
+        tx.append(12345);
+        tx.commit().unwrap();
+        assert_eq!(idx.get(n as RowId).unwrap(), Some(12345));
+        assert_eq!(idx.get(n as RowId - 1).unwrap(), Some((n as Offset - 1) * 3));
+
+        let file = fs::File::open("IDX_paged").unwrap();
+        let paged = Paged::new(file, n, 3 * PAGE_SIZE + 1);
+        for row in 0..n {
+            assert_eq!(paged.get(row).unwrap(), row as Offset * 3);
+            assert!(paged.loaded_pages() <= 3);
+        }
+
+        fs::remove_file("IDX_paged").unwrap();
+    }
+}
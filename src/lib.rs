@@ -1,15 +1,40 @@
-pub type RowId = u32;
+#![cfg_attr(feature = "bench", feature(test))]
+
+pub type RowId = u64;
 pub type Offset = u64;
 
 pub mod index;
 pub mod log;
 pub mod engine;
-// pub mod flex;
+mod meta;
+mod manifest;
+mod segment;
+mod times;
+mod tombstone;
+mod versions;
+mod hole;
+mod audit;
+mod operation;
+mod hooks;
+mod watch;
+pub mod flex;
 
 pub use crate::index::Index;
+pub use crate::index::IndexMode;
 pub use crate::log::Log;
 pub use crate::engine::Engine;
+pub use crate::engine::OpenOptions;
 pub use crate::engine::Transaction;
+pub use crate::engine::Reader;
+pub use crate::engine::Subscription;
+pub use crate::engine::Truncated;
+pub use crate::engine::Deleted;
+pub use crate::engine::Rewound;
+pub use crate::audit::Redaction;
+pub use crate::operation::Operation;
+pub use crate::hooks::PendingCommit;
+pub use crate::flex::EvictionPolicy;
+pub use crate::segment::{SegmentInfo, Tier};
 
 #[cfg(test)]
 mod tests {
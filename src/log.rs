@@ -2,32 +2,70 @@
 //!
 //! The log mod provides an abstraction over the underlying log
 //! file. Its main purpose is to provide fast random reads, based on a
-//! flex cache wrapper (todo). Right now, it's the same as a
+//! flex cache wrapper.  Without a cache, it's the same as a
 //! std::fs::File.
 
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Result, Seek, SeekFrom, BufWriter};
-use std::path::Path;
+use std::io::{Read, Write, Result, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::mem;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use byteorder::WriteBytesExt;
 use byteorder::LittleEndian;
-use positioned_io::ReadAt;
+use positioned_io::{ReadAt, ReadBytesAtExt};
 
 use crate::Offset;
+use crate::flex::{DEFAULT_FLEX_SIZE, Flex, Policy};
 
 #[allow(unused)]
 const DEFAULT_READ_BUF_SIZE: usize = 1024;
-const DEFAULT_WRITE_BUF_SIZE: usize = 64 * 1024 * 1024;
 
 /// The length of an entry must be representable by this type.
 pub(crate) type EntrySize = u16;
 
+/// Every entry starts with its length.
+pub(crate) const HEADER_SIZE: u64 = mem::size_of::<EntrySize>() as u64;
+
 /// In-memory representation of a log file.
 ///
 /// Reads and writes should not be mixed.
 pub struct Log {
     /// The raw file handle.
     file: File,
+    path: PathBuf,
+    /// Caches positioned reads, if enabled.  It reads through a file
+    /// handle of its own, so that its seeks don't move the offset of
+    /// other handles.  The lock serializes the positioned reads of
+    /// the log, so concurrent readers of one log take turns.
+    cache: Option<Mutex<Flex<File>>>,
+    /// The budget the capacity of the cache is a share of, if any.
+    budget: Option<Arc<CacheBudget>>,
+    /// Bytes a transaction buffers before writing them back.
+    write_buffer: usize,
+}
+
+/// A cache size shared by several logs, e.g. the segments of a
+/// repository.  It's split evenly between the logs using it, which
+/// adjust their caches on their next read.
+pub(crate) struct CacheBudget {
+    size: usize,
+    logs: AtomicUsize,
+}
+
+impl CacheBudget {
+    pub fn new(size: usize) -> Arc<CacheBudget> {
+        Arc::new(CacheBudget {
+            size,
+            logs: AtomicUsize::new(0),
+        })
+    }
+
+    /// The capacity of the cache of each log.
+    fn share(&self) -> usize {
+        self.size / self.logs.load(Ordering::SeqCst).max(1)
+    }
 }
 
 impl Log {
@@ -37,31 +75,175 @@ impl Log {
             .append(true)
             .create(true)
             .read(true)
-            .open(path)?;
+            .open(&path)?;
         Ok(Log {
             file,
+            path: path.as_ref().to_path_buf(),
+            cache: None,
+            budget: None,
+            write_buffer: DEFAULT_FLEX_SIZE,
         })
     }
 
-    /// Start a new transaction to append entries to the log file.
+    /// Open an existing log file for reading only.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Log> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&path)?;
+        Ok(Log {
+            file,
+            path: path.as_ref().to_path_buf(),
+            cache: None,
+            budget: None,
+            write_buffer: DEFAULT_FLEX_SIZE,
+        })
+    }
+
+    /// Cache up to `capacity` bytes of the file in memory for
+    /// positioned reads, evicting pages as `policy` chooses.  A
+    /// capacity of 0 disables the cache.
     ///
-    /// Do not read the log file before the transaction is committed or cancelled.
+    /// Appends and truncations through the log update the cache.
+    /// Other changes of the file must be followed by `invalidate`.
+    pub fn with_cache(mut self, capacity: usize, policy: Box<dyn Policy>) -> Result<Log> {
+        self.cache = match capacity {
+            0 => None,
+            _ => Some(Mutex::new(Flex::with_policy(capacity, policy, File::open(&self.path)?))),
+        };
+        Ok(self)
+    }
+
+    /// Cache the file like `with_cache`, with a share of `budget` as
+    /// the capacity.  A budget of 0 disables the cache.
+    pub(crate) fn with_shared_cache(self, budget: &Arc<CacheBudget>, policy: Box<dyn Policy>) -> Result<Log> {
+        if budget.size == 0 {
+            return Ok(self)
+        }
+        let mut log = self.with_cache(budget.size, policy)?;
+        budget.logs.fetch_add(1, Ordering::SeqCst);
+        log.budget = Some(budget.clone());
+        Ok(log)
+    }
+
+    /// Buffer up to `bytes` bytes of the entries appended by a
+    /// transaction in memory.  The default is 64 MiB.
+    pub fn with_write_buffer(mut self, bytes: usize) -> Log {
+        self.write_buffer = bytes;
+        self
+    }
+
+    /// Forget the cached bytes of [start, end), and the length of the
+    /// file, after the file is changed behind the log.
+    pub fn invalidate(&self, start: Offset, end: Offset) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().invalidate(start, end);
+        }
+    }
+
+    /// Read `range` of the file into the cache ahead of its use.  See
+    /// `Flex::prefetch`.  Does nothing without a cache.
+    pub fn prefetch(&self, range: Range<Offset>) -> Result<()> {
+        match &self.cache {
+            Some(_) => self.lock_cache()?.prefetch(range),
+            None => Ok(()),
+        }
+    }
+
+    /// Lock the cache, which must be enabled, and fit it in its share
+    /// of the budget.
+    fn lock_cache(&self) -> Result<MutexGuard<'_, Flex<File>>> {
+        let mut cache = self.cache.as_ref().unwrap().lock().unwrap();
+        if let Some(budget) = &self.budget {
+            cache.set_capacity(budget.share())?;
+        }
+        Ok(cache)
+    }
+
+    /// Start a new transaction to append entries to the log file.
     pub fn transaction(&mut self) -> Result<Transaction<'_>> {
-        let tail = self.file.seek(SeekFrom::End(0))?;
+        self.begin()
+    }
+
+    /// Start a new transaction through a shared reference.
+    ///
+    /// The caller must make sure there is at most one transaction at
+    /// a time.  Positioned reads (`ReadAt`) of entries written before
+    /// the transaction are safe while it is open.
+    pub(crate) fn begin(&self) -> Result<Transaction<'_>> {
+        let tail = self.size()?;
+        // The writer has a file handle of its own, so that reading the
+        // page at the tail and writing back don't race with other
+        // handles over the file offset.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)?;
+        let mut writer = Flex::with_capacity(self.write_buffer, file);
+        writer.seek(SeekFrom::Start(tail))?;
         Ok(Transaction {
-            writer: BufWriter::with_capacity(DEFAULT_WRITE_BUF_SIZE, self.file.try_clone()?),
+            writer,
             log: self,
+            start: tail,
             tail,
+            committed: false,
         })
     }
 
-    pub fn reset(&mut self) -> Result<()> {
-        Ok(())
+    /// Current length of the log file.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Skip `n` entries, starting from the entry at `offset`, and
+    /// return the offset of the entry after them.
+    pub fn skip_entries(&self, mut offset: Offset, n: usize) -> Result<Offset> {
+        for _ in 0..n {
+            offset += HEADER_SIZE + self.entry_len(offset)? as u64;
+        }
+        Ok(offset)
+    }
+
+    /// Count the complete entries starting from `offset`, up to `max`
+    /// entries.  Returns the count and the end of the last counted
+    /// entry.  A torn entry at the end of the file is not counted.
+    pub fn scan(&self, mut offset: Offset, max: usize) -> Result<(usize, Offset)> {
+        let len = self.size()?;
+        let mut count = 0;
+        while count < max && offset + HEADER_SIZE <= len {
+            let end = offset + HEADER_SIZE + self.entry_len(offset)? as u64;
+            if end > len {
+                break
+            }
+            offset = end;
+            count += 1;
+        }
+        Ok((count, offset))
+    }
+
+    /// Cut the log file at `len`, and make sure it's durable.
+    pub fn truncate(&self, len: u64) -> Result<()> {
+        self.file.set_len(len)?;
+        self.invalidate(len, u64::MAX);
+        self.sync_data()
+    }
+
+    /// Read the length of the entry at `offset`, without reading its
+    /// contents.
+    pub fn entry_len(&self, offset: Offset) -> Result<usize> {
+        Ok(self.read_u16_at::<LittleEndian>(offset)? as usize)
+    }
+
+    /// Read the bytes of the entry at `offset`, starting from byte
+    /// `start` of the entry, to fill `buf`.
+    ///
+    /// The caller must make sure the range lies within the entry.
+    pub fn read_entry_at(&self, offset: Offset, start: usize, buf: &mut [u8]) -> Result<()> {
+        self.read_exact_at(offset + HEADER_SIZE + start as u64, buf)
     }
 
     /// Try to write the data to the log file, and make sure the
     /// writes do happen.
-    fn sync_data(&mut self)  -> Result<()> {
+    fn sync_data(&self) -> Result<()> {
         self.file.sync_data()
     }
 }
@@ -85,15 +267,37 @@ impl Seek for Log {
 impl ReadAt for Log {
     #[inline]
     fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
-        self.file.read_at(pos, buf)
+        match &self.cache {
+            Some(_) => {
+                let mut cache = self.lock_cache()?;
+                cache.seek(SeekFrom::Start(pos))?;
+                cache.read(buf)
+            }
+            None => self.file.read_at(pos, buf),
+        }
+    }
+}
+
+impl Drop for Log {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.logs.fetch_sub(1, Ordering::SeqCst);
+        }
     }
 }
 
 /// Atomic updates to the log file.
+///
+/// If the transaction is dropped without committing, the log file is
+/// cut back to where the transaction started.
 pub struct Transaction<'a> {
-    log: &'a mut Log,
-    writer: BufWriter<File>,
+    log: &'a Log,
+    /// Caches the appended entries until they're flushed, or evicted
+    /// for room.
+    writer: Flex<File>,
+    start: u64,
     tail: u64,
+    committed: bool,
 }
 
 impl<'a> Transaction<'a> {
@@ -101,7 +305,6 @@ impl<'a> Transaction<'a> {
         assert!(entry.len() <= EntrySize::max_value() as usize);
         unsafe { let _ = std::mem::transmute::<u16, EntrySize>(0); }
         // FIXME: how should we align entries?
-        // Note, `seek` will invalidate the buffer.
         let offset = self.tail;
         self.writer.write_u16::<LittleEndian>(entry.len() as EntrySize)?;
         self.writer.write_all(entry)?;
@@ -111,7 +314,23 @@ impl<'a> Transaction<'a> {
 
     pub fn commit(mut self) -> Result<()> {
         self.writer.flush()?;
-        self.log.sync_data()
+        self.log.sync_data()?;
+        // The cache may have seen a part of the transaction.
+        self.log.invalidate(self.start, u64::MAX);
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.committed {
+            // Discard what's not written back yet, so that nothing is
+            // left to be written after the file is cut.
+            self.writer.invalidate(self.start, u64::MAX);
+            let _ = self.log.file.set_len(self.start);
+            self.log.invalidate(self.start, u64::MAX);
+        }
     }
 }
 
@@ -119,6 +338,7 @@ impl<'a> Transaction<'a> {
 mod tests {
     use super::*;
     use std::fs;
+    use crate::flex::EvictionPolicy;
     use crate::tests::*;
 
     #[test]
@@ -133,6 +353,111 @@ mod tests {
         fs::remove_file("LOG1").unwrap();
     }
 
+    #[test]
+    fn rollback_and_scan() {
+        let filename = "LOG_rollback";
+        ensure_nonexistent(filename);
+
+        let mut log = Log::open(filename).unwrap();
+        let mut tx = log.transaction().unwrap();
+        tx.append(b"one").unwrap();
+        let second = tx.append(b"second").unwrap();
+        tx.commit().unwrap();
+        let len = log.size().unwrap();
+
+        let mut tx = log.transaction().unwrap();
+        tx.append(b"discarded").unwrap();
+        drop(tx);
+        assert_eq!(log.size().unwrap(), len);
+
+        // Including what a small write buffer wrote back already.
+        let mut log = log.with_write_buffer(4096);
+        let mut tx = log.transaction().unwrap();
+        for _ in 0..100 {
+            tx.append(&[0; 100]).unwrap();
+        }
+        assert!(fs::metadata(filename).unwrap().len() > len);
+        drop(tx);
+        assert_eq!(log.size().unwrap(), len);
+
+        assert_eq!(log.skip_entries(0, 1).unwrap(), second);
+        assert_eq!(log.scan(0, 10).unwrap(), (2, len));
+        assert_eq!(log.scan(0, 1).unwrap(), (1, second));
+
+        // A torn entry is not counted.
+        let mut file = OpenOptions::new().append(true).open(filename).unwrap();
+        file.write_all(&[5, 0, b'a']).unwrap();
+        assert_eq!(log.scan(second, 10).unwrap(), (1, len));
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn cached_reads() {
+        let filename = "LOG_cached";
+        ensure_nonexistent(filename);
+
+        let mut log = Log::open(filename).unwrap()
+            .with_cache(64 * 1024, EvictionPolicy::default().build()).unwrap();
+        let mut tx = log.transaction().unwrap();
+        let first = tx.append(b"first").unwrap();
+        tx.commit().unwrap();
+        let mut buf = [0; 6];
+        log.read_entry_at(first, 0, &mut buf[..5]).unwrap();
+        assert_eq!(&buf[..5], b"first");
+
+        // Appends to a cached page are seen.
+        let mut tx = log.transaction().unwrap();
+        let second = tx.append(b"second").unwrap();
+        tx.commit().unwrap();
+        log.read_entry_at(second, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"second");
+
+        // So are truncations, and rewrites after them.
+        log.truncate(second).unwrap();
+        assert!(log.entry_len(second).is_err());
+        let mut tx = log.transaction().unwrap();
+        assert_eq!(tx.append(b"other!").unwrap(), second);
+        tx.commit().unwrap();
+        log.read_entry_at(second, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"other!");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn shared_cache() {
+        let filenames = ["LOG_shared1", "LOG_shared2"];
+        for filename in &filenames {
+            ensure_nonexistent(filename);
+            let mut log = Log::open(filename).unwrap();
+            let mut tx = log.transaction().unwrap();
+            tx.append(&[1; 60000]).unwrap();
+            tx.commit().unwrap();
+        }
+
+        // The logs split the budget, and the cached bytes of each fit
+        // in its share.
+        let budget = CacheBudget::new(64 * 1024);
+        let first = Log::open(filenames[0]).unwrap()
+            .with_shared_cache(&budget, EvictionPolicy::default().build()).unwrap();
+        first.prefetch(0..60000).unwrap();
+        assert!(first.cache.as_ref().unwrap().lock().unwrap().cached() > 32 * 1024);
+        let second = Log::open(filenames[1]).unwrap()
+            .with_shared_cache(&budget, EvictionPolicy::default().build()).unwrap();
+        first.prefetch(0..60000).unwrap();
+        second.prefetch(0..60000).unwrap();
+        for log in &[&first, &second] {
+            assert!(log.cache.as_ref().unwrap().lock().unwrap().cached() <= 32 * 1024);
+        }
+        drop(second);
+        assert_eq!(budget.share(), 64 * 1024);
+
+        for filename in &filenames {
+            fs::remove_file(filename).unwrap();
+        }
+    }
+
     #[test]
     fn bulk_append() {
         let filename = "LOG_bulk_append";
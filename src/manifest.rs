@@ -0,0 +1,211 @@
+//! # Manifest
+//!
+//! The manifest mod manages the `MANIFEST` file of a repository,
+//! which lists its segments in order.  Each line is the ID of a
+//! segment and its base row, separated by a space.  Rows before the
+//! first row are truncated, and the first row is recorded in a line
+//! `first_row <row>` if it's not the base row of the first segment.
+//! A tail truncation in progress is recorded in a line
+//! `truncating <row>`, where `row` is the new next row, so that it's
+//! finished after a crash.  Likewise, a segment whose compacted files
+//! are being moved into place is recorded in a line `compacting <id>`.
+//! A segment moved to the cold storage directory is recorded in a
+//! line `cold <id>`.  The generation in a line `generation <n>` is
+//! bumped whenever rows are removed from the end, so that followers
+//! know to drop what they've read of them.  The file is replaced
+//! atomically whenever it changes.
+//!
+//! A repository without a manifest has a single segment `0`, based at
+//! row 0, which is how repositories were laid out before segments.
+
+use std::fs::File;
+use std::io::{Result, Error, ErrorKind, Read};
+use std::path::Path;
+
+use crate::RowId;
+use crate::meta::replace_file;
+use crate::segment::Tier;
+
+/// A segment as recorded in the manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub id: u64,
+    pub base: RowId,
+}
+
+/// In-memory representation of the `MANIFEST` file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    /// The first row which is not truncated.
+    pub first_row: RowId,
+    pub segments: Vec<Entry>,
+    /// The new next row of an unfinished tail truncation.
+    pub truncating: Option<RowId>,
+    /// The segment of an unfinished compaction.
+    pub compacting: Option<u64>,
+    /// The segments in the cold storage directory.  IDs of segments
+    /// which are not listed are ignored.
+    pub cold: Vec<u64>,
+    /// Bumped by every tail truncation and reset.
+    pub generation: u64,
+}
+
+impl Default for Manifest {
+    fn default() -> Manifest {
+        Manifest {
+            first_row: 0,
+            segments: vec![Entry { id: 0, base: 0 }],
+            truncating: None,
+            compacting: None,
+            cold: Vec::new(),
+            generation: 0,
+        }
+    }
+}
+
+impl Manifest {
+    /// Load the manifest of the repository `dir`.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Manifest> {
+        let mut text = String::new();
+        match File::open(dir.as_ref().join("MANIFEST")) {
+            Ok(mut file) => { file.read_to_string(&mut text)?; }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Manifest::default()),
+            Err(e) => return Err(e),
+        }
+
+        let mut first_row = None;
+        let mut truncating = None;
+        let mut compacting = None;
+        let mut cold = Vec::new();
+        let mut generation = 0;
+        let mut segments: Vec<Entry> = Vec::new();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let mut parts = line.split(' ');
+            let entry = match (parts.next(), parts.next(), parts.next()) {
+                (Some("first_row"), Some(row), None) => {
+                    first_row = Some(row.parse().map_err(|_| invalid_data(line))?);
+                    continue
+                }
+                (Some("truncating"), Some(row), None) => {
+                    truncating = Some(row.parse().map_err(|_| invalid_data(line))?);
+                    continue
+                }
+                (Some("compacting"), Some(id), None) => {
+                    compacting = Some(id.parse().map_err(|_| invalid_data(line))?);
+                    continue
+                }
+                (Some("cold"), Some(id), None) => {
+                    cold.push(id.parse().map_err(|_| invalid_data(line))?);
+                    continue
+                }
+                (Some("generation"), Some(n), None) => {
+                    generation = n.parse().map_err(|_| invalid_data(line))?;
+                    continue
+                }
+                (Some(id), Some(base), None) => Entry {
+                    id: id.parse().map_err(|_| invalid_data(line))?,
+                    base: base.parse().map_err(|_| invalid_data(line))?,
+                },
+                _ => return Err(invalid_data(line)),
+            };
+            // Segments are listed in the order of their rows.
+            if let Some(last) = segments.last() {
+                if entry.id <= last.id || entry.base < last.base {
+                    return Err(invalid_data(line))
+                }
+            }
+            segments.push(entry);
+        }
+        if segments.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "empty MANIFEST"))
+        }
+        let first_row = first_row.unwrap_or(segments[0].base);
+        if first_row < segments[0].base {
+            return Err(Error::new(ErrorKind::InvalidData, "first row before the first segment"))
+        }
+        Ok(Manifest { first_row, segments, truncating, compacting, cold, generation })
+    }
+
+    /// Write the manifest of the repository `dir`, replacing the old
+    /// one atomically.
+    pub fn store<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let mut text = String::new();
+        if self.first_row != self.segments[0].base {
+            text.push_str(&format!("first_row {}\n", self.first_row));
+        }
+        if let Some(row) = self.truncating {
+            text.push_str(&format!("truncating {}\n", row));
+        }
+        if let Some(id) = self.compacting {
+            text.push_str(&format!("compacting {}\n", id));
+        }
+        if self.generation > 0 {
+            text.push_str(&format!("generation {}\n", self.generation));
+        }
+        for entry in &self.segments {
+            if self.is_cold(entry.id) {
+                text.push_str(&format!("cold {}\n", entry.id));
+            }
+        }
+        for entry in &self.segments {
+            text.push_str(&format!("{} {}\n", entry.id, entry.base));
+        }
+        replace_file(dir.as_ref(), "MANIFEST", text.as_bytes())
+    }
+
+    /// Whether the segment `id` is in the cold storage directory.
+    pub fn is_cold(&self, id: u64) -> bool {
+        self.cold.contains(&id)
+    }
+
+    /// The storage tier of the segment `id`.
+    pub fn tier(&self, id: u64) -> Tier {
+        if self.is_cold(id) {
+            Tier::Cold
+        } else {
+            Tier::Hot
+        }
+    }
+}
+
+fn invalid_data(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("malformed MANIFEST entry: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::tests::*;
+
+    #[test]
+    fn store_and_load() {
+        ensure_dir_nonexistent("dbmanifest");
+        fs::create_dir("dbmanifest").unwrap();
+
+        let mut manifest = Manifest::load("dbmanifest").unwrap();
+        assert_eq!(manifest, Manifest::default());
+        manifest.segments.push(Entry { id: 1, base: 100 });
+        manifest.segments.push(Entry { id: 2, base: 250 });
+        manifest.store("dbmanifest").unwrap();
+        assert_eq!(Manifest::load("dbmanifest").unwrap(), manifest);
+
+        manifest.first_row = 120;
+        manifest.truncating = Some(300);
+        manifest.compacting = Some(1);
+        manifest.cold = vec![1];
+        manifest.generation = 3;
+        manifest.segments.remove(0);
+        manifest.store("dbmanifest").unwrap();
+        assert_eq!(Manifest::load("dbmanifest").unwrap(), manifest);
+        fs::write("dbmanifest/MANIFEST", "first_row 10\n1 20\n").unwrap();
+        assert!(Manifest::load("dbmanifest").is_err());
+
+        fs::write("dbmanifest/MANIFEST", "0 0\n2 10\n1 20\n").unwrap();
+        assert!(Manifest::load("dbmanifest").is_err());
+        fs::write("dbmanifest/MANIFEST", "").unwrap();
+        assert!(Manifest::load("dbmanifest").is_err());
+
+        fs::remove_dir_all("dbmanifest").unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! # Meta
+//!
+//! The meta mod manages the `META` file of a repository, which
+//! records format parameters that must not change once data is
+//! written.
+//!
+//! The file is plain text.  Each line is a key and a value,
+//! separated by a space.  It is replaced atomically as a whole.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Result, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// In-memory representation of the `META` file.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Meta {
+    entries: BTreeMap<String, String>,
+}
+
+impl Meta {
+    /// Load the meta file in the repository `dir`.  A missing file
+    /// is the same as an empty one.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Meta> {
+        let mut text = String::new();
+        match File::open(meta_path(dir.as_ref())) {
+            Ok(mut file) => { file.read_to_string(&mut text)?; }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut entries = BTreeMap::new();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap();
+            let value = parts.next().ok_or_else(|| invalid_data(line))?;
+            entries.insert(key.to_string(), value.to_string());
+        }
+        Ok(Meta { entries })
+    }
+
+    /// Get and parse the value of `key`.
+    pub fn get<T: FromStr>(&self, key: &str) -> Result<Option<T>> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(value) => value.parse().map(Some).map_err(|_| invalid_data(value)),
+        }
+    }
+
+    pub fn set<T: ToString>(&mut self, key: &str, value: T) {
+        self.entries.insert(key.to_string(), value.to_string());
+    }
+
+    /// Write the meta file of the repository `dir`, replacing the old
+    /// one atomically.
+    pub fn store<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let mut text = String::new();
+        for (key, value) in &self.entries {
+            text.push_str(&format!("{} {}\n", key, value));
+        }
+        replace_file(dir.as_ref(), "META", text.as_bytes())
+    }
+}
+
+fn meta_path(dir: &Path) -> PathBuf {
+    dir.join("META")
+}
+
+/// Replace the file `name` in the repository `dir` with `contents`
+/// atomically: readers see either the old file or the new one, even
+/// after a crash.
+pub(crate) fn replace_file(dir: &Path, name: &str, contents: &[u8]) -> Result<()> {
+    let path = dir.join(name);
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    File::open(dir)?.sync_all()
+}
+
+fn invalid_data(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("malformed META entry: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn store_and_load() {
+        ensure_dir_nonexistent("dbmeta");
+        fs::create_dir("dbmeta").unwrap();
+
+        let mut meta = Meta::load("dbmeta").unwrap();
+        assert_eq!(meta.get::<u32>("answer").unwrap(), None);
+        meta.set("answer", 42);
+        meta.set("greeting", "hello world");
+        meta.store("dbmeta").unwrap();
+
+        let meta = Meta::load("dbmeta").unwrap();
+        assert_eq!(meta.get::<u32>("answer").unwrap(), Some(42));
+        assert_eq!(meta.get::<String>("greeting").unwrap().unwrap(), "hello world");
+        assert!(meta.get::<u32>("greeting").is_err());
+
+        fs::remove_dir_all("dbmeta").unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+//! # Operation
+//!
+//! The operation mod applies user-defined operations to committed
+//! entries.  An operation folds the entries into a derived state, like
+//! a counter or a latest-by-key map.  Its state is checkpointed in the
+//! file `OP_<name>` of the repository, so reopening only replays the
+//! rows after the checkpoint.
+//!
+//! A checkpoint file holds the next row the operation has to apply, as
+//! a little-endian u64, followed by the saved state.  It is replaced
+//! atomically as a whole.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Result, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::RowId;
+use crate::meta::replace_file;
+
+/// A user-defined operation, applied to each committed entry in row
+/// order.
+pub trait Operation: Send {
+    /// Fold the entry of `row` into the state.  If it fails, the row
+    /// is applied again later.
+    fn apply(&mut self, row: RowId, entry: &[u8]) -> Result<()>;
+
+    /// Serialize the state for a checkpoint.
+    fn save(&self) -> Vec<u8>;
+
+    /// Replace the state with a saved one.  An empty slice is the
+    /// initial state, before any row is applied.
+    fn load(&mut self, state: &[u8]) -> Result<()>;
+}
+
+/// Lets registered operations be found by their concrete type.
+trait Registered: Operation {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<O: Operation + 'static> Registered for O {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct Slot {
+    op: Box<dyn Registered>,
+    /// The next row the operation has to apply.
+    next_row: RowId,
+    /// The next row recorded in the last checkpoint.
+    checkpoint: RowId,
+}
+
+/// The operations registered on an engine, by name.
+#[derive(Default)]
+pub(crate) struct Operations {
+    slots: BTreeMap<String, Slot>,
+}
+
+impl Operations {
+    /// Register `op` as `name`, and restore its state from the
+    /// checkpoint in the repository `dir`, if any.
+    pub fn register<O: Operation + 'static>(&mut self, dir: &Path, name: &str, mut op: O) -> Result<()> {
+        let valid = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+        if !valid {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid operation name"))
+        }
+        if self.slots.contains_key(name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "operation is already registered"))
+        }
+        let next_row = match load_checkpoint(dir, name)? {
+            Some((row, state)) => {
+                op.load(&state)?;
+                row
+            }
+            None => 0,
+        };
+        self.slots.insert(name.to_string(), Slot { op: Box::new(op), next_row, checkpoint: next_row });
+        Ok(())
+    }
+
+    /// The operation registered as `name`, if it's an `O`.
+    pub fn get<O: Operation + 'static>(&self, name: &str) -> Option<&O> {
+        self.slots.get(name).and_then(|slot| slot.op.as_any().downcast_ref())
+    }
+
+    /// The first row some operation has to apply, if any.
+    pub fn next_row(&self) -> Option<RowId> {
+        self.slots.values().map(|slot| slot.next_row).min()
+    }
+
+    /// Apply the entry of `row` to the operations which are at it.
+    pub fn apply(&mut self, row: RowId, entry: &[u8]) -> Result<()> {
+        for slot in self.slots.values_mut().filter(|slot| slot.next_row == row) {
+            slot.op.apply(row, entry)?;
+            slot.next_row += 1;
+        }
+        Ok(())
+    }
+
+    /// Skip the rows before `row`, which can't be read.
+    pub fn skip_to(&mut self, row: RowId) {
+        for slot in self.slots.values_mut() {
+            slot.next_row = slot.next_row.max(row);
+        }
+    }
+
+    /// Checkpoint the operations which applied at least `rows` rows
+    /// since their last checkpoint.
+    pub fn checkpoint(&mut self, dir: &Path, rows: RowId) -> Result<()> {
+        for (name, slot) in &mut self.slots {
+            if slot.next_row >= slot.checkpoint.saturating_add(rows.max(1)) {
+                store_checkpoint(dir, name, slot.next_row, &slot.op.save())?;
+                slot.checkpoint = slot.next_row;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checkpoint the operations whose last checkpoint is before
+    /// `row`, before the rows there are truncated.
+    pub fn checkpoint_before(&mut self, dir: &Path, row: RowId) -> Result<()> {
+        for (name, slot) in &mut self.slots {
+            if slot.checkpoint < row && slot.next_row > slot.checkpoint {
+                store_checkpoint(dir, name, slot.next_row, &slot.op.save())?;
+                slot.checkpoint = slot.next_row;
+            }
+        }
+        Ok(())
+    }
+
+    /// Go back to the checkpoints in the repository `dir`, or to the
+    /// initial states, after another process removed rows the
+    /// operations may have applied.
+    pub fn reload(&mut self, dir: &Path) -> Result<()> {
+        for (name, slot) in &mut self.slots {
+            let (row, state) = load_checkpoint(dir, name)?.unwrap_or_default();
+            slot.op.load(&state)?;
+            slot.next_row = row;
+            slot.checkpoint = row;
+        }
+        Ok(())
+    }
+
+    /// Forget the rows from `end` on, after they are removed.  The
+    /// operations which applied them go back to their checkpoints, or
+    /// to their initial states if the checkpoints cover them too.
+    pub fn rewind(&mut self, dir: &Path, end: RowId) -> Result<()> {
+        for (name, slot) in &mut self.slots {
+            if slot.next_row <= end {
+                continue
+            }
+            match load_checkpoint(dir, name)?.filter(|&(row, _)| row <= end) {
+                Some((row, state)) => {
+                    slot.op.load(&state)?;
+                    slot.next_row = row;
+                }
+                None => {
+                    slot.op.load(&[])?;
+                    slot.next_row = 0;
+                }
+            }
+            slot.checkpoint = slot.next_row;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the checkpoints in the repository `dir` which cover rows
+/// from `end` on, before such rows are written again.  This includes
+/// the checkpoints of operations not registered right now.
+pub(crate) fn remove_checkpoints_after(dir: &Path, end: RowId) -> Result<()> {
+    for dir_entry in fs::read_dir(dir)? {
+        let file_name = dir_entry?.file_name();
+        let name = match file_name.to_str().and_then(|name| name.strip_prefix("OP_")) {
+            Some(name) if !name.ends_with(".tmp") => name,
+            _ => continue,
+        };
+        if let Some((row, _)) = load_checkpoint(dir, name)? {
+            if row > end {
+                fs::remove_file(checkpoint_path(dir, name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn checkpoint_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("OP_{}", name))
+}
+
+fn load_checkpoint(dir: &Path, name: &str) -> Result<Option<(RowId, Vec<u8>)>> {
+    match fs::read(checkpoint_path(dir, name)) {
+        Ok(mut data) => {
+            if data.len() < 8 {
+                return Err(Error::new(ErrorKind::InvalidData, "malformed operation checkpoint"))
+            }
+            let row = LittleEndian::read_u64(&data);
+            Ok(Some((row, data.split_off(8))))
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn store_checkpoint(dir: &Path, name: &str, row: RowId, state: &[u8]) -> Result<()> {
+    let mut data = vec![0; 8];
+    LittleEndian::write_u64(&mut data, row);
+    data.extend_from_slice(state);
+    replace_file(dir, &format!("OP_{}", name), &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl Operation for Sum {
+        fn apply(&mut self, _row: RowId, entry: &[u8]) -> Result<()> {
+            self.0 += entry[0] as u64;
+            Ok(())
+        }
+
+        fn save(&self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+
+        fn load(&mut self, state: &[u8]) -> Result<()> {
+            self.0 = if state.is_empty() { 0 } else { LittleEndian::read_u64(state) };
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checkpoints() {
+        ensure_dir_nonexistent("dbops");
+        fs::create_dir("dbops").unwrap();
+        let dir = Path::new("dbops");
+
+        let mut ops = Operations::default();
+        assert!(ops.register(dir, "bad name", Sum::default()).is_err());
+        ops.register(dir, "sum", Sum::default()).unwrap();
+        assert!(ops.register(dir, "sum", Sum::default()).is_err());
+        for row in 0..10 {
+            ops.apply(row, &[row as u8]).unwrap();
+        }
+        ops.checkpoint(dir, 8).unwrap();
+        ops.apply(10, &[10]).unwrap();
+        assert_eq!(ops.get::<Sum>("sum").unwrap().0, 55);
+
+        // Rewinding goes back to the checkpoint, or starts over.
+        ops.rewind(dir, 10).unwrap();
+        assert_eq!((ops.next_row(), ops.get::<Sum>("sum").unwrap().0), (Some(10), 45));
+        ops.rewind(dir, 5).unwrap();
+        assert_eq!((ops.next_row(), ops.get::<Sum>("sum").unwrap().0), (Some(0), 0));
+        remove_checkpoints_after(dir, 10).unwrap();
+        assert!(checkpoint_path(dir, "sum").exists());
+        remove_checkpoints_after(dir, 5).unwrap();
+        assert!(!checkpoint_path(dir, "sum").exists());
+
+        for row in 0..3 {
+            ops.apply(row, &[1]).unwrap();
+        }
+        ops.checkpoint_before(dir, 2).unwrap();
+        let mut ops = Operations::default();
+        ops.register(dir, "sum", Sum::default()).unwrap();
+        assert_eq!((ops.next_row(), ops.get::<Sum>("sum").unwrap().0), (Some(3), 3));
+        ops.apply(3, &[1]).unwrap();
+        ops.reload(dir).unwrap();
+        assert_eq!((ops.next_row(), ops.get::<Sum>("sum").unwrap().0), (Some(3), 3));
+
+        fs::remove_dir_all("dbops").unwrap();
+    }
+}
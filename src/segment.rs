@@ -0,0 +1,546 @@
+//! # Segment
+//!
+//! A repository is split into segments.  A segment is a log file
+//! `LOGn` with its index file `IDXn`, and holds a contiguous range of
+//! rows starting from its base row.  A sparse index keeps its number
+//! of committed rows in the rows file `ROWSn`.  The time file `TIMEn`
+//! records when its rows are committed, the tombstone file `DELn`
+//! lists its deleted rows, and the version log `VERn` and the overlay
+//! file `OVRn` hold the newer versions of its updated rows.  Only the
+//! last segment is appended to; the others are sealed.
+//!
+//! The files of a segment are either in the repository directory, the
+//! hot tier, or in the cold storage directory, the cold tier.  Sealed
+//! segments can be moved to the cold tier.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result, ErrorKind};
+use std::ops::Range;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use positioned_io::WriteAt;
+
+use crate::{Index, IndexMode, Log, RowId, Offset};
+use crate::log::{CacheBudget, HEADER_SIZE};
+use crate::manifest::Entry;
+use crate::times::Times;
+use crate::tombstone::Tombstones;
+use crate::versions::Versions;
+use crate::hole::DeadRanges;
+use crate::flex::EvictionPolicy;
+
+/// The storage tier of a segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tier {
+    /// In the repository directory.
+    Hot,
+    /// In the cold storage directory.  See
+    /// `OpenOptions::cold_dir`.
+    Cold,
+}
+
+/// Information about a segment.  See `Engine::segments`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub id: u64,
+    /// The first row of the segment.  Some of its rows may be
+    /// truncated.
+    pub base: RowId,
+    /// The row after the last committed row of the segment.
+    pub end: RowId,
+    pub tier: Tier,
+}
+
+pub(crate) struct Segment {
+    id: u64,
+    base: RowId,
+    /// The directory holding the files.
+    dir: PathBuf,
+    tier: Tier,
+    pub index: Index,
+    pub log: Log,
+    pub times: Times,
+    pub tombstones: Tombstones,
+    pub versions: Versions,
+    /// Dead ranges of the log file.
+    dead: DeadRanges,
+}
+
+/// How the log file of a segment is cached.
+#[derive(Clone)]
+pub(crate) struct LogCache {
+    /// The bytes cached for all segments.  A size of 0 disables the
+    /// cache.
+    pub budget: Arc<CacheBudget>,
+    /// Bytes of appended entries buffered by a transaction.
+    pub write_buffer: usize,
+    pub policy: EvictionPolicy,
+}
+
+impl Segment {
+    /// Open a segment, whose files are in `dir` of the storage `tier`.
+    /// The files of a writable segment are created if they don't
+    /// exist, and whatever is left behind by an interrupted commit is
+    /// dropped.  The log file is cached for reads as `cache` says.
+    pub fn open(dir: &Path, tier: Tier, entry: Entry, mode: IndexMode, stride: usize, read_only: bool, cache: LogCache) -> Result<Segment> {
+        let log = if read_only {
+            Log::open_read_only(log_path(dir, entry.id))?
+        } else {
+            Log::open(log_path(dir, entry.id))?
+        };
+        let log = log.with_shared_cache(&cache.budget, cache.policy.build())?
+            .with_write_buffer(cache.write_buffer);
+        let index = Index::open_sparse(index_path(dir, entry.id), rows_path(dir, entry.id), mode, read_only, stride)?;
+        let times = Times::open(time_path(dir, entry.id), read_only)?;
+        let tombstones = Tombstones::open(tombstone_path(dir, entry.id), read_only)?;
+        let versions = Versions::open(version_path(dir, entry.id), overlay_path(dir, entry.id), read_only)?;
+
+        let end = index.recover(&log)?;
+        if !read_only && log.size()? > end {
+            log.truncate(end)?;
+        }
+        Ok(Segment {
+            id: entry.id,
+            base: entry.base,
+            dir: PathBuf::from(dir),
+            tier,
+            index,
+            log,
+            times,
+            tombstones,
+            versions,
+            dead: DeadRanges::new(),
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The directory holding the files of this segment.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn tier(&self) -> Tier {
+        self.tier
+    }
+
+    pub fn info(&self) -> SegmentInfo {
+        SegmentInfo {
+            id: self.id,
+            base: self.base,
+            end: self.end(),
+            tier: self.tier,
+        }
+    }
+
+    /// The first row of this segment.
+    pub fn base(&self) -> RowId {
+        self.base
+    }
+
+    /// The row after the last committed row of this segment.
+    pub fn end(&self) -> RowId {
+        self.base + self.rows()
+    }
+
+    /// Number of committed rows.
+    pub fn rows(&self) -> RowId {
+        self.index.next_row()
+    }
+
+    /// Bytes taken by the files of this segment.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.log.size()? + self.index.size()? + self.versions.size()?)
+    }
+
+    pub fn entry(&self) -> Entry {
+        Entry { id: self.id, base: self.base }
+    }
+
+    /// Time of the last commit to this segment, in milliseconds since
+    /// the Unix epoch.  Without recorded commit times, it's when the
+    /// log file was last modified.
+    pub fn last_commit(&self) -> Result<u64> {
+        if let Some(time) = self.times.last_commit() {
+            return Ok(time)
+        }
+        let modified = fs::metadata(log_path(&self.dir, self.id))?.modified()?;
+        Ok(modified.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0))
+    }
+
+    /// Drop the rows from `rows` on, relative to the base row, and
+    /// make it durable.  This can be repeated after a crash.
+    pub fn truncate(&self, rows: RowId) -> Result<()> {
+        if let Some(end) = self.locate(self.base + rows)? {
+            self.index.truncate(rows)?;
+            self.log.truncate(end)?;
+            self.dead.truncate(end);
+        }
+        self.times.truncate(rows)
+    }
+
+    /// Load the deletions and updates which are in effect when the
+    /// next row of the repository is `next_row`.
+    pub fn load_changes(&self, next_row: RowId) -> Result<()> {
+        self.tombstones.load(next_row)?;
+        self.versions.load(next_row)
+    }
+
+    /// Drop the deletions and updates which are not in effect when the
+    /// next row of the repository is `next_row`, and make it durable.
+    pub fn truncate_changes(&self, next_row: RowId) -> Result<()> {
+        self.tombstones.truncate(next_row)?;
+        self.versions.truncate(next_row)
+    }
+
+    /// Drop the deletions and updates of the rows from `end` on, after
+    /// a tail truncation of the repository at `end`, and make it
+    /// durable.  Those of the rows before `end` stay in effect, even if
+    /// their transactions committed after `end`.
+    pub fn truncate_changes_after(&self, end: RowId) -> Result<()> {
+        let rows = end.saturating_sub(self.base);
+        self.tombstones.truncate_rows(rows, end)?;
+        self.versions.truncate_rows(rows, end)
+    }
+
+    /// Whether `row` is deleted.
+    pub fn is_deleted(&self, row: RowId) -> bool {
+        row >= self.base && self.tombstones.contains(row - self.base)
+    }
+
+    /// Delete `row`, and overwrite its payload with zeros in the log
+    /// file in place.  The length header is kept, so the log file
+    /// still parses.  `next_row` is the next row of the repository.
+    /// This can be repeated after a crash.
+    pub fn redact(&self, row: RowId, next_row: RowId) -> Result<()> {
+        let offset = match self.locate(row)? {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        self.tombstones.write(&[row - self.base], next_row)?;
+        self.tombstones.load(next_row)?;
+
+        erase(&self.log, &log_path(&self.dir, self.id), &[offset])?;
+        self.mark_deleted(&[row - self.base])?;
+        let versions = self.versions.get(row - self.base);
+        if !versions.is_empty() {
+            erase(self.versions.log(), &version_path(&self.dir, self.id), &versions)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the payloads of deleted rows, relative to the base row,
+    /// dead.  Their length headers are kept, so the log file still
+    /// parses.
+    pub fn mark_deleted(&self, rows: &[RowId]) -> Result<()> {
+        for &row in rows {
+            if let Some(offset) = self.locate(self.base + row)? {
+                let start = offset + HEADER_SIZE;
+                self.dead.add(start, start + self.log.entry_len(offset)? as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the part of the log file before `first_row` dead.
+    ///
+    /// The part is cut where the index starts looking for `first_row`,
+    /// and never covers the last indexed row, which recovery reads.
+    /// So only rows before `first_row` become unreadable.
+    pub fn mark_truncated(&self, first_row: RowId) -> Result<()> {
+        let rows = self.rows();
+        if first_row <= self.base || rows == 0 {
+            return Ok(())
+        }
+        let row = (first_row - self.base).min(rows - 1);
+        if let Some((end, _)) = self.index.locate(row)? {
+            self.dead.add(0, end);
+        }
+        Ok(())
+    }
+
+    /// Punch the dead ranges out of the log file, and return the
+    /// number of bytes of disk space released.
+    pub fn punch_holes(&self) -> Result<u64> {
+        if self.dead.len() == 0 {
+            return Ok(0)
+        }
+        let file = OpenOptions::new().write(true).open(log_path(&self.dir, self.id))?;
+        self.dead.punch(&file)
+    }
+
+    /// Number of dead bytes not punched out yet.
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead.len()
+    }
+
+    /// Whether compacting this segment would drop any payload.  Rows
+    /// before `first_row` are truncated, and not looked at.
+    pub fn has_garbage(&self, first_row: RowId) -> Result<bool> {
+        if !self.versions.is_empty() {
+            return Ok(true)
+        }
+        let first = first_row.saturating_sub(self.base);
+        for row in self.tombstones.rows().into_iter().filter(|&row| row >= first) {
+            if let Some(offset) = self.locate(self.base + row)? {
+                if self.log.entry_len(offset)? > 0 {
+                    return Ok(true)
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Write a copy of this segment into `LOGn.compact`,
+    /// `IDXn.compact` and `ROWSn.compact`, where deleted rows and
+    /// rows before `first_row` are empty entries, and updated rows
+    /// only keep their latest version.  Every row keeps its row ID.  See `finish_compaction`.
+    pub fn write_compacted(&self, first_row: RowId) -> Result<()> {
+        let dir = &self.dir;
+        remove_compacted(dir, self.id)?;
+        let log = Log::open(compacted(log_path(dir, self.id)))?;
+        let index = Index::open_sparse(compacted(index_path(dir, self.id)), compacted(rows_path(dir, self.id)), IndexMode::Memory, false, self.index.stride())?;
+
+        let mut log_tx = log.begin()?;
+        let mut index_tx = index.begin();
+        // The part of the log file before the first row may be
+        // punched out, so it's not read.
+        let first = first_row.saturating_sub(self.base).min(self.rows());
+        let mut offset = self.locate(self.base + first)?.unwrap_or(0);
+        for _ in 0..first {
+            index_tx.append(log_tx.append(&[])?);
+        }
+        for row in first..self.rows() {
+            let len = self.log.entry_len(offset)?;
+            let new_offset = if self.tombstones.contains(row) {
+                log_tx.append(&[])?
+            } else {
+                let (log, latest) = match self.versions.get(row).last() {
+                    Some(&latest) => (self.versions.log(), latest),
+                    None => (&self.log, offset),
+                };
+                let mut buf = vec![0; log.entry_len(latest)?];
+                log.read_entry_at(latest, 0, &mut buf[..])?;
+                log_tx.append(&buf)?
+            };
+            index_tx.append(new_offset);
+            offset += HEADER_SIZE + len as u64;
+        }
+        log_tx.commit()?;
+        index_tx.commit()
+    }
+
+    /// Durably copy the files of this segment into `dir`.  Each file
+    /// is written under a temporary name, and renamed once it's
+    /// complete.  See `remove_copying`.
+    pub fn copy_to(&self, dir: &Path) -> Result<()> {
+        for from in &paths(&self.dir, self.id) {
+            let name = from.file_name().unwrap();
+            let tmp = copying(dir.join(name));
+            match fs::copy(from, &tmp) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+            File::open(&tmp)?.sync_all()?;
+            fs::rename(&tmp, dir.join(name))?;
+        }
+        File::open(dir)?.sync_all()
+    }
+
+    /// Pick up the rows committed by another process.  See
+    /// `Index::refresh`.
+    pub fn refresh(&self) -> Result<()> {
+        // The writer may have cut the log file and written it again,
+        // so nothing cached is trusted.
+        self.log.invalidate(0, u64::MAX);
+        self.index.refresh()?;
+        self.index.recover(&self.log)?;
+        Ok(())
+    }
+
+    /// Find a version of a committed row: the log holding it and its
+    /// offset there.  Version 0 is the appended entry, and the later
+    /// ones are its updates.  `None` stands for the latest version.
+    pub fn locate_version(&self, row: RowId, version: Option<usize>) -> Result<Option<(&Log, Offset)>> {
+        let offset = match self.locate(row)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let versions = self.versions.get(row - self.base);
+        match version.unwrap_or(versions.len()) {
+            0 => Ok(Some((&self.log, offset))),
+            n if n <= versions.len() => Ok(Some((self.versions.log(), versions[n - 1]))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Read the log of the committed rows in `rows` into its cache
+    /// ahead of a scan.  Newer versions of updated rows are not read.
+    pub fn prefetch(&self, rows: Range<RowId>) -> Result<()> {
+        let start = rows.start.max(self.base);
+        let end = rows.end.min(self.end());
+        if start >= end {
+            return Ok(())
+        }
+        let (first, last) = match (self.locate(start)?, self.locate(end - 1)?) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(()),
+        };
+        let end = last + HEADER_SIZE + self.log.entry_len(last)? as u64;
+        self.log.prefetch(first..end)
+    }
+
+    /// Find the offset of a committed row in the log file.
+    pub fn locate(&self, row: RowId) -> Result<Option<Offset>> {
+        if row < self.base {
+            return Ok(None)
+        }
+        match self.index.locate(row - self.base)? {
+            Some((offset, skip)) => Ok(Some(self.log.skip_entries(offset, skip)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) fn log_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("LOG{}", id))
+}
+
+pub(crate) fn index_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("IDX{}", id))
+}
+
+pub(crate) fn rows_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("ROWS{}", id))
+}
+
+pub(crate) fn time_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("TIME{}", id))
+}
+
+pub(crate) fn tombstone_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("DEL{}", id))
+}
+
+pub(crate) fn version_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("VER{}", id))
+}
+
+pub(crate) fn overlay_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("OVR{}", id))
+}
+
+/// Overwrite the payloads of the entries at `offsets` of `log`, whose
+/// file is `path`, with zeros, and sync.  The length headers are kept,
+/// so the log file still parses.
+fn erase(log: &Log, path: &Path, offsets: &[Offset]) -> Result<()> {
+    // The log file is opened for appending, which ignores the offset
+    // of positioned writes.
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut erased = Vec::new();
+    for &offset in offsets {
+        let len = log.entry_len(offset)?;
+        file.write_all_at(offset + HEADER_SIZE, &vec![0; len])?;
+        erased.push((offset + HEADER_SIZE, offset + HEADER_SIZE + len as u64));
+    }
+    file.sync_data()?;
+    for (start, end) in erased {
+        log.invalidate(start, end);
+    }
+    Ok(())
+}
+
+/// All files of the segment `id` in `dir`.
+fn paths(dir: &Path, id: u64) -> [PathBuf; 7] {
+    [
+        log_path(dir, id), index_path(dir, id), rows_path(dir, id), time_path(dir, id),
+        tombstone_path(dir, id), version_path(dir, id), overlay_path(dir, id),
+    ]
+}
+
+fn compacted(path: PathBuf) -> PathBuf {
+    path.with_extension("compact")
+}
+
+fn copying(path: PathBuf) -> PathBuf {
+    path.with_extension("copying")
+}
+
+/// Replace the files of the segment `id` with the compacted ones,
+/// and remove its versions, which are folded into them.  This can be
+/// repeated after a crash.
+pub(crate) fn finish_compaction(dir: &Path, id: u64) -> Result<()> {
+    for path in &[index_path(dir, id), rows_path(dir, id), log_path(dir, id)] {
+        match fs::rename(compacted(path.clone()), path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    for path in &[overlay_path(dir, id), version_path(dir, id)] {
+        match fs::remove_file(path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    File::open(dir)?.sync_all()
+}
+
+/// Remove the compacted files of the segment `id`, if any.
+pub(crate) fn remove_compacted(dir: &Path, id: u64) -> Result<()> {
+    for path in &[compacted(log_path(dir, id)), compacted(index_path(dir, id)), compacted(rows_path(dir, id))] {
+        match fs::remove_file(path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Remove the files of the segment `id`, including partial copies
+/// and compacted files.
+pub(crate) fn remove(dir: &Path, id: u64) -> Result<()> {
+    for path in &paths(dir, id) {
+        for path in &[path.clone(), copying(path.clone()), compacted(path.clone())] {
+            match fs::remove_file(path) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove the files of all segments before the segment `id`, which
+/// may be left behind by a truncation interrupted after updating the
+/// manifest.
+pub(crate) fn remove_before(dir: &Path, id: u64) -> Result<()> {
+    remove_if(dir, |n| n < id)
+}
+
+/// Remove the files of all segments after the segment `id`.
+pub(crate) fn remove_after(dir: &Path, id: u64) -> Result<()> {
+    remove_if(dir, |n| n > id)
+}
+
+fn remove_if<F: Fn(u64) -> bool>(dir: &Path, pred: F) -> Result<()> {
+    for dir_entry in fs::read_dir(dir)? {
+        let name = dir_entry?.file_name();
+        let id = name.to_str()
+            .and_then(|name| ["LOG", "IDX", "ROWS", "TIME", "DEL", "VER", "OVR"].iter().find_map(|prefix| name.strip_prefix(prefix)))
+            .and_then(|n| n.split('.').next().unwrap().parse::<u64>().ok())
+            .filter(|&n| pred(n));
+        if let Some(id) = id {
+            remove(dir, id)?;
+        }
+    }
+    Ok(())
+}
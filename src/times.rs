@@ -0,0 +1,186 @@
+//! # Times
+//!
+//! The times mod manages the time file `TIMEn` of a segment, which
+//! records when each transaction is committed.  Each record is the
+//! first row of a transaction, relative to the base row of the
+//! segment, followed by the commit time in milliseconds since the
+//! Unix epoch, both as little-endian u64.
+//!
+//! Commit times never decrease within a file, even if the clock goes
+//! backwards, so that records can be searched by time.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use positioned_io::ReadAt;
+
+use crate::RowId;
+
+/// Size of a record.
+const RECORD_SIZE: u64 = 16;
+
+pub(crate) struct Times {
+    /// Segments written before commit times were recorded have no
+    /// time file.
+    file: Option<File>,
+    /// Time of the last record.
+    last: AtomicU64,
+}
+
+impl Times {
+    /// Open a time file, or create it if the segment is writable.  A
+    /// partially written record at the end is dropped.
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Times> {
+        let file = if read_only {
+            match File::open(path) {
+                Ok(file) => Some(file),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            }
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .read(true)
+                .open(path)?;
+            let len = file.metadata()?.len();
+            if len % RECORD_SIZE != 0 {
+                file.set_len(len - len % RECORD_SIZE)?;
+            }
+            Some(file)
+        };
+
+        let times = Times {
+            file,
+            last: AtomicU64::new(0),
+        };
+        let n = times.len()?;
+        if n > 0 {
+            times.last.store(times.record(n - 1)?.1, Ordering::SeqCst);
+        }
+        Ok(times)
+    }
+
+    /// Record that the transaction starting from `row` is committed
+    /// now.
+    ///
+    /// The record is not synced: if it's lost in a crash, the rows of
+    /// the transaction just count as committed by the one before.
+    pub fn record_commit(&self, row: RowId) -> Result<()> {
+        let time = now_millis().max(self.last.load(Ordering::SeqCst));
+
+        let mut buf = [0; RECORD_SIZE as usize];
+        LittleEndian::write_u64_into(&[row, time], &mut buf);
+        if let Some(mut file) = self.file.as_ref() {
+            file.write_all(&buf)?;
+        }
+        self.last.store(time, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Drop the records of transactions starting from `row` or later.
+    pub fn truncate(&self, row: RowId) -> Result<()> {
+        let (mut lo, mut hi) = (0, self.len()?);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.record(mid)?.0 < row {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if let Some(file) = &self.file {
+            file.set_len(lo * RECORD_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Time of the last commit, in milliseconds since the Unix epoch,
+    /// if any is recorded.
+    pub fn last_commit(&self) -> Option<u64> {
+        Some(self.last.load(Ordering::SeqCst)).filter(|&time| time > 0)
+    }
+
+    /// Find the first row committed at or after `time`, in
+    /// milliseconds since the Unix epoch.
+    pub fn first_row_since(&self, time: u64) -> Result<Option<RowId>> {
+        let (mut lo, mut hi) = (0, self.len()?);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.record(mid)?.1 < time {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo < self.len()? {
+            Ok(Some(self.record(lo)?.0))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Number of complete records.
+    fn len(&self) -> Result<u64> {
+        match &self.file {
+            Some(file) => Ok(file.metadata()?.len() / RECORD_SIZE),
+            None => Ok(0),
+        }
+    }
+
+    fn record(&self, i: u64) -> Result<(RowId, u64)> {
+        let mut buf = [0; RECORD_SIZE as usize];
+        self.file.as_ref().unwrap().read_exact_at(i * RECORD_SIZE, &mut buf)?;
+        Ok((LittleEndian::read_u64(&buf[..8]), LittleEndian::read_u64(&buf[8..])))
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis().try_into().unwrap())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+    use crate::tests::*;
+
+    #[test]
+    fn record_and_find() {
+        ensure_nonexistent("TIME_times");
+        let times = Times::open("TIME_times", false).unwrap();
+        assert_eq!(times.first_row_since(0).unwrap(), None);
+
+        times.record_commit(0).unwrap();
+        times.record_commit(3).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let between = times.last.load(Ordering::SeqCst) + 1;
+        times.record_commit(7).unwrap();
+        assert_eq!(times.first_row_since(0).unwrap(), Some(0));
+        assert_eq!(times.first_row_since(between).unwrap(), Some(7));
+        assert_eq!(times.first_row_since(u64::MAX).unwrap(), None);
+        drop(times);
+
+        // A torn record is dropped.
+        fs::OpenOptions::new().append(true).open("TIME_times").unwrap().write_all(&[1, 2, 3]).unwrap();
+        let times = Times::open("TIME_times", false).unwrap();
+        assert_eq!(times.len().unwrap(), 3);
+        assert_eq!(times.first_row_since(between).unwrap(), Some(7));
+
+        times.truncate(5).unwrap();
+        assert_eq!(times.len().unwrap(), 2);
+        assert_eq!(times.first_row_since(between).unwrap(), None);
+
+        fs::remove_file("TIME_times").unwrap();
+    }
+}
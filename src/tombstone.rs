@@ -0,0 +1,253 @@
+//! # Tombstone
+//!
+//! The tombstone mod manages the tombstone file `DELn` of a segment,
+//! which lists its deleted rows.  Each record is a deleted row,
+//! relative to the base row of the segment, followed by the next row
+//! of the repository after the deleting transaction commits, both as
+//! little-endian u64.
+//!
+//! Records are written before the rest of their transaction, and
+//! only take effect once the repository reaches their next row.  So a
+//! deletion is atomic with the rows appended in the same transaction.
+//! If the transaction fails to commit, its records are cut off again,
+//! so that a later transaction reaching the same row doesn't pick
+//! them up.
+//!
+//! A tail truncation blanks the records of the removed rows, by
+//! setting their row to `NO_ROW`, and lowers the next row of the
+//! others to the new next row of the repository, so that they stay in
+//! effect.
+
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use byteorder::{ByteOrder, LittleEndian};
+use positioned_io::{ReadAt, WriteAt};
+
+use crate::RowId;
+
+/// Size of a record.
+const RECORD_SIZE: u64 = 16;
+
+/// The row of a blanked record, which stands for no row.
+pub(crate) const NO_ROW: RowId = RowId::MAX;
+
+pub(crate) struct Tombstones {
+    /// Segments without deleted rows may have no tombstone file.
+    file: Option<File>,
+    path: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+/// The records in effect.
+struct Loaded {
+    rows: BTreeSet<RowId>,
+    /// Number of records in effect.
+    records: u64,
+}
+
+impl Tombstones {
+    /// Open a tombstone file, or create it if the segment is
+    /// writable.  No records are in effect until `load` or `truncate`.
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Tombstones> {
+        let file = if read_only {
+            match File::open(&path) {
+                Ok(file) => Some(file),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            }
+        } else {
+            Some(OpenOptions::new()
+                 .create(true)
+                 .append(true)
+                 .read(true)
+                 .open(&path)?)
+        };
+
+        Ok(Tombstones {
+            file,
+            path: path.as_ref().to_path_buf(),
+            loaded: RwLock::new(Loaded {
+                rows: BTreeSet::new(),
+                records: 0,
+            }),
+        })
+    }
+
+    /// Load the new records which are in effect when the next row of
+    /// the repository is `next_row`.
+    pub fn load(&self, next_row: RowId) -> Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut loaded = self.loaded.write().unwrap();
+        let len = file.metadata()?.len() / RECORD_SIZE;
+        while loaded.records < len {
+            let (row, commit) = read_record(file, loaded.records)?;
+            if commit > next_row {
+                break
+            }
+            if row != NO_ROW {
+                loaded.rows.insert(row);
+            }
+            loaded.records += 1;
+        }
+        Ok(())
+    }
+
+    /// Durably write the records of a transaction which ends at
+    /// `commit`.  They take effect after `load`.
+    pub fn write(&self, rows: &[RowId], commit: RowId) -> Result<()> {
+        let mut file = self.file.as_ref().unwrap();
+        let mut buf = vec![0; rows.len() * RECORD_SIZE as usize];
+        for (i, &row) in rows.iter().enumerate() {
+            let record = &mut buf[i * RECORD_SIZE as usize..(i + 1) * RECORD_SIZE as usize];
+            LittleEndian::write_u64_into(&[row, commit], record);
+        }
+        file.write_all(&buf)?;
+        file.sync_data()
+    }
+
+    /// Current length of the tombstone file.
+    pub fn size(&self) -> Result<u64> {
+        match &self.file {
+            Some(file) => Ok(file.metadata()?.len()),
+            None => Ok(0),
+        }
+    }
+
+    /// Cut the tombstone file back to `len`, as it was before a
+    /// transaction which failed to commit, and make it durable.
+    pub fn rollback(&self, len: u64) -> Result<()> {
+        let file = self.file.as_ref().unwrap();
+        file.set_len(len)?;
+        file.sync_data()
+    }
+
+    /// Reload the records in effect when the next row of the
+    /// repository is `next_row`, drop the others, and make it durable.
+    ///
+    /// After opening a writable segment, this drops the records of
+    /// transactions which never committed.
+    pub fn truncate(&self, next_row: RowId) -> Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut loaded = self.loaded.write().unwrap();
+        let len = file.metadata()?.len() / RECORD_SIZE;
+        let mut records = 0;
+        let mut rows = BTreeSet::new();
+        while records < len {
+            let (row, commit) = read_record(file, records)?;
+            if commit > next_row {
+                break
+            }
+            if row != NO_ROW {
+                rows.insert(row);
+            }
+            records += 1;
+        }
+        if file.metadata()?.len() != records * RECORD_SIZE {
+            file.set_len(records * RECORD_SIZE)?;
+            file.sync_data()?;
+        }
+        *loaded = Loaded { rows, records };
+        Ok(())
+    }
+
+    /// Blank the records of the rows from `end` on, after the
+    /// repository is truncated to have `next_row` as its next row, and
+    /// bring the others into effect.  All records must be committed.
+    /// Records are rewritten in place, so this can be repeated after a
+    /// crash.
+    pub fn truncate_rows(&self, end: RowId, next_row: RowId) -> Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        // The file is opened for appending, which ignores the offset of
+        // positioned writes.
+        let mut writer = OpenOptions::new().write(true).open(&self.path)?;
+        let len = file.metadata()?.len() / RECORD_SIZE;
+        for i in 0..len {
+            let (row, commit) = read_record(file, i)?;
+            if (row >= end && row != NO_ROW) || commit > next_row {
+                let row = if row >= end { NO_ROW } else { row };
+                let mut buf = [0; RECORD_SIZE as usize];
+                LittleEndian::write_u64_into(&[row, commit.min(next_row)], &mut buf);
+                writer.write_all_at(i * RECORD_SIZE, &buf)?;
+            }
+        }
+        writer.sync_data()?;
+        self.truncate(next_row)
+    }
+
+    pub fn contains(&self, row: RowId) -> bool {
+        self.loaded.read().unwrap().rows.contains(&row)
+    }
+
+    /// All deleted rows, in order.
+    pub fn rows(&self) -> Vec<RowId> {
+        self.loaded.read().unwrap().rows.iter().cloned().collect()
+    }
+}
+
+fn read_record(file: &File, i: u64) -> Result<(RowId, RowId)> {
+    let mut buf = [0; RECORD_SIZE as usize];
+    file.read_exact_at(i * RECORD_SIZE, &mut buf)?;
+    Ok((LittleEndian::read_u64(&buf[..8]), LittleEndian::read_u64(&buf[8..])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::tests::*;
+
+    #[test]
+    fn in_effect() {
+        ensure_nonexistent("DEL_tombstone");
+        let tombstones = Tombstones::open("DEL_tombstone", false).unwrap();
+        tombstones.write(&[1, 3], 10).unwrap();
+        tombstones.write(&[5], 12).unwrap();
+        tombstones.load(11).unwrap();
+        assert_eq!(tombstones.rows(), vec![1, 3]);
+        tombstones.load(12).unwrap();
+        assert!(tombstones.contains(5));
+
+        // The last transaction didn't commit.
+        tombstones.write(&[7], 13).unwrap();
+        drop(tombstones);
+        let tombstones = Tombstones::open("DEL_tombstone", false).unwrap();
+        tombstones.truncate(12).unwrap();
+        assert_eq!(tombstones.rows(), vec![1, 3, 5]);
+        assert_eq!(fs::metadata("DEL_tombstone").unwrap().len(), 3 * RECORD_SIZE);
+
+        tombstones.truncate(11).unwrap();
+        assert_eq!(tombstones.rows(), vec![1, 3]);
+
+        // A transaction which fails to commit leaves nothing behind.
+        let len = tombstones.size().unwrap();
+        tombstones.write(&[8], 11).unwrap();
+        tombstones.rollback(len).unwrap();
+        tombstones.load(11).unwrap();
+        assert_eq!(tombstones.rows(), vec![1, 3]);
+
+        // A tail truncation to row 3 drops the deletion of row 3 only,
+        // even though both took effect after it.
+        tombstones.truncate_rows(3, 3).unwrap();
+        assert_eq!(tombstones.rows(), vec![1]);
+        drop(tombstones);
+        let tombstones = Tombstones::open("DEL_tombstone", false).unwrap();
+        tombstones.truncate(3).unwrap();
+        assert_eq!(tombstones.rows(), vec![1]);
+        assert_eq!(tombstones.size().unwrap(), 2 * RECORD_SIZE);
+
+        fs::remove_file("DEL_tombstone").unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+//! # Versions
+//!
+//! The versions mod manages the newer versions of updated rows of a
+//! segment.  Their entries are appended to the version log `VERn`,
+//! which has the same format as a log file, and the overlay file
+//! `OVRn` points the rows to them.  Each record of the overlay file
+//! is an updated row, relative to the base row of the segment, the
+//! offset of the new version in the version log, and the next row of
+//! the repository after the updating transaction commits, all as
+//! little-endian u64.
+//!
+//! Like tombstones, records only take effect once the repository
+//! reaches their next row, so an update is atomic with the rest of its
+//! transaction, and both files are cut back if it fails to commit.  A
+//! tail truncation treats them like tombstones too.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use byteorder::{ByteOrder, LittleEndian};
+use positioned_io::{ReadAt, WriteAt};
+
+use crate::{Log, RowId, Offset};
+use crate::tombstone::NO_ROW;
+
+/// Size of a record.
+const RECORD_SIZE: u64 = 24;
+
+pub(crate) struct Versions {
+    /// Segments without updated rows may have no version files.
+    files: Option<(Log, File)>,
+    overlay_path: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+/// The records in effect.
+struct Loaded {
+    /// Offsets of the newer versions of each updated row, oldest
+    /// first.
+    rows: HashMap<RowId, Vec<Offset>>,
+    /// Number of records in effect.
+    records: u64,
+}
+
+impl Versions {
+    /// Open the version log and the overlay file, or create them if
+    /// the segment is writable.  No records are in effect until `load`
+    /// or `truncate`.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(log_path: P, overlay_path: Q, read_only: bool) -> Result<Versions> {
+        let files = if read_only {
+            match (Log::open_read_only(log_path), File::open(&overlay_path)) {
+                (Ok(log), Ok(file)) => Some((log, file)),
+                (Err(ref e), _) | (_, Err(ref e)) if e.kind() == ErrorKind::NotFound => None,
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            }
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .read(true)
+                .open(&overlay_path)?;
+            Some((Log::open(log_path)?, file))
+        };
+
+        Ok(Versions {
+            files,
+            overlay_path: overlay_path.as_ref().to_path_buf(),
+            loaded: RwLock::new(Loaded {
+                rows: HashMap::new(),
+                records: 0,
+            }),
+        })
+    }
+
+    /// The version log.
+    pub fn log(&self) -> &Log {
+        &self.files.as_ref().unwrap().0
+    }
+
+    /// Load the new records which are in effect when the next row of
+    /// the repository is `next_row`.
+    pub fn load(&self, next_row: RowId) -> Result<()> {
+        let file = match &self.files {
+            Some((_, file)) => file,
+            None => return Ok(()),
+        };
+        let mut loaded = self.loaded.write().unwrap();
+        let len = file.metadata()?.len() / RECORD_SIZE;
+        while loaded.records < len {
+            let (row, offset, commit) = read_record(file, loaded.records)?;
+            if commit > next_row {
+                break
+            }
+            if row != NO_ROW {
+                loaded.rows.entry(row).or_default().push(offset);
+            }
+            loaded.records += 1;
+        }
+        Ok(())
+    }
+
+    /// Durably write the new versions of a transaction which ends at
+    /// `commit`.  They take effect after `load`.
+    pub fn write(&self, updates: &[(RowId, &[u8])], commit: RowId) -> Result<()> {
+        let (log, mut file) = self.files.as_ref().map(|(log, file)| (log, file)).unwrap();
+        let mut tx = log.begin()?;
+        let mut buf = vec![0; updates.len() * RECORD_SIZE as usize];
+        for (i, &(row, entry)) in updates.iter().enumerate() {
+            let offset = tx.append(entry)?;
+            let record = &mut buf[i * RECORD_SIZE as usize..(i + 1) * RECORD_SIZE as usize];
+            LittleEndian::write_u64_into(&[row, offset, commit], record);
+        }
+        tx.commit()?;
+        file.write_all(&buf)?;
+        file.sync_data()
+    }
+
+    /// Current lengths of the version log and the overlay file.
+    pub fn lens(&self) -> Result<(u64, u64)> {
+        match &self.files {
+            Some((log, file)) => Ok((log.size()?, file.metadata()?.len())),
+            None => Ok((0, 0)),
+        }
+    }
+
+    /// Cut the version log and the overlay file back to `lens`, as
+    /// they were before a transaction which failed to commit, and make
+    /// it durable.
+    pub fn rollback(&self, lens: (u64, u64)) -> Result<()> {
+        let (log, file) = self.files.as_ref().unwrap();
+        file.set_len(lens.1)?;
+        file.sync_data()?;
+        log.truncate(lens.0)
+    }
+
+    /// Reload the records in effect when the next row of the
+    /// repository is `next_row`, drop the others, and make it durable.
+    ///
+    /// After opening a writable segment, this drops the records of
+    /// transactions which never committed.  Their entries are left in
+    /// the version log until compaction.
+    pub fn truncate(&self, next_row: RowId) -> Result<()> {
+        let file = match &self.files {
+            Some((_, file)) => file,
+            None => return Ok(()),
+        };
+        let mut loaded = self.loaded.write().unwrap();
+        let len = file.metadata()?.len() / RECORD_SIZE;
+        let mut records = 0;
+        let mut rows: HashMap<RowId, Vec<Offset>> = HashMap::new();
+        while records < len {
+            let (row, offset, commit) = read_record(file, records)?;
+            if commit > next_row {
+                break
+            }
+            if row != NO_ROW {
+                rows.entry(row).or_default().push(offset);
+            }
+            records += 1;
+        }
+        if file.metadata()?.len() != records * RECORD_SIZE {
+            file.set_len(records * RECORD_SIZE)?;
+            file.sync_data()?;
+        }
+        *loaded = Loaded { rows, records };
+        Ok(())
+    }
+
+    /// Blank the records of the rows from `end` on, after the
+    /// repository is truncated to have `next_row` as its next row, and
+    /// bring the others into effect.  See `Tombstones::truncate_rows`.
+    /// The entries of the blanked records are left in the version log
+    /// until compaction.
+    pub fn truncate_rows(&self, end: RowId, next_row: RowId) -> Result<()> {
+        let file = match &self.files {
+            Some((_, file)) => file,
+            None => return Ok(()),
+        };
+        let mut writer = OpenOptions::new().write(true).open(&self.overlay_path)?;
+        let len = file.metadata()?.len() / RECORD_SIZE;
+        for i in 0..len {
+            let (row, offset, commit) = read_record(file, i)?;
+            if (row >= end && row != NO_ROW) || commit > next_row {
+                let row = if row >= end { NO_ROW } else { row };
+                let mut buf = [0; RECORD_SIZE as usize];
+                LittleEndian::write_u64_into(&[row, offset, commit.min(next_row)], &mut buf);
+                writer.write_all_at(i * RECORD_SIZE, &buf)?;
+            }
+        }
+        writer.sync_data()?;
+        self.truncate(next_row)
+    }
+
+    /// Get the offsets of the newer versions of `row` in the version
+    /// log, oldest first.
+    pub fn get(&self, row: RowId) -> Vec<Offset> {
+        self.loaded.read().unwrap().rows.get(&row).cloned().unwrap_or_default()
+    }
+
+    /// Bytes taken by the version log and the overlay file.
+    pub fn size(&self) -> Result<u64> {
+        match &self.files {
+            Some((log, file)) => Ok(log.size()? + file.metadata()?.len()),
+            None => Ok(0),
+        }
+    }
+
+    /// Whether any row is updated.
+    pub fn is_empty(&self) -> bool {
+        self.loaded.read().unwrap().rows.is_empty()
+    }
+}
+
+fn read_record(file: &File, i: u64) -> Result<(RowId, Offset, RowId)> {
+    let mut buf = [0; RECORD_SIZE as usize];
+    file.read_exact_at(i * RECORD_SIZE, &mut buf)?;
+    let mut record = [0; 3];
+    LittleEndian::read_u64_into(&buf, &mut record);
+    Ok((record[0], record[1], record[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::tests::*;
+
+    #[test]
+    fn in_effect() {
+        ensure_nonexistent("VER_versions");
+        ensure_nonexistent("OVR_versions");
+        let versions = Versions::open("VER_versions", "OVR_versions", false).unwrap();
+        versions.write(&[(1, b"one"), (2, b"two")], 10).unwrap();
+        versions.write(&[(1, b"uno")], 12).unwrap();
+        versions.load(11).unwrap();
+        assert_eq!(versions.get(1).len(), 1);
+        versions.load(12).unwrap();
+        let offsets = versions.get(1);
+        assert_eq!(offsets.len(), 2);
+        let mut buf = [0; 3];
+        versions.log().read_entry_at(offsets[1], 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"uno");
+
+        drop(versions);
+        let versions = Versions::open("VER_versions", "OVR_versions", false).unwrap();
+        versions.truncate(11).unwrap();
+        assert_eq!(versions.get(1).len(), 1);
+        assert_eq!(versions.get(2).len(), 1);
+        assert_eq!(fs::metadata("OVR_versions").unwrap().len(), 2 * RECORD_SIZE);
+
+        // A transaction which fails to commit leaves nothing behind.
+        let lens = versions.lens().unwrap();
+        versions.write(&[(2, b"dos")], 11).unwrap();
+        versions.rollback(lens).unwrap();
+        versions.load(11).unwrap();
+        assert_eq!(versions.get(2).len(), 1);
+        assert_eq!(versions.lens().unwrap(), lens);
+
+        // A tail truncation to row 2 keeps the updates of row 1 only.
+        versions.truncate_rows(2, 2).unwrap();
+        assert_eq!(versions.get(1).len(), 1);
+        assert!(versions.get(2).is_empty());
+
+        fs::remove_file("VER_versions").unwrap();
+        fs::remove_file("OVR_versions").unwrap();
+    }
+}
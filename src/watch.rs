@@ -0,0 +1,97 @@
+//! # Watch
+//!
+//! The watch mod lets a follower wait for another process to change
+//! a file, or the files in a directory.  On Linux, it is based on
+//! inotify.  Elsewhere, it falls back to polling at a short interval.
+
+use std::io::Result;
+use std::path::Path;
+use std::time::Duration;
+
+/// Polling interval used when there is no better way to wait.
+#[allow(unused)]
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Watches a file, or the files in a directory, for modifications.
+pub(crate) struct Watcher {
+    #[cfg(target_os = "linux")]
+    fd: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Watcher> {
+        use std::ffi::CString;
+        use std::io::Error;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        unsafe {
+            let fd = libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK);
+            if fd < 0 {
+                return Err(Error::last_os_error())
+            }
+            if libc::inotify_add_watch(fd, path.as_ptr(), libc::IN_MODIFY | libc::IN_CREATE | libc::IN_MOVED_TO) < 0 {
+                let err = Error::last_os_error();
+                libc::close(fd);
+                return Err(err)
+            }
+            Ok(Watcher { fd })
+        }
+    }
+
+    /// Wait until the file is modified, or `timeout` elapses.
+    ///
+    /// Returns true if the file may have been modified.  Spurious
+    /// wake-ups are possible.
+    pub fn wait(&self, timeout: Duration) -> Result<bool> {
+        use std::convert::TryInto;
+        use std::io::{Error, ErrorKind};
+
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout: libc::c_int = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ready < 0 {
+            let err = Error::last_os_error();
+            return if err.kind() == ErrorKind::Interrupted { Ok(true) } else { Err(err) }
+        }
+        if ready == 0 {
+            return Ok(false)
+        }
+
+        // Drain the queued events.  Their contents don't matter.
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n <= 0 {
+                break
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    pub fn new<P: AsRef<Path>>(_path: P) -> Result<Watcher> {
+        Ok(Watcher {})
+    }
+
+    /// Sleep for a short while, since there's no way to know whether
+    /// the file is modified.  Always returns true.
+    pub fn wait(&self, timeout: Duration) -> Result<bool> {
+        std::thread::sleep(timeout.min(POLL_INTERVAL));
+        Ok(true)
+    }
+}